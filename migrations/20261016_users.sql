@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS users
+(
+    uuid BLOB PRIMARY KEY NOT NULL,
+    created DATETIME NOT NULL,
+    updated DATETIME NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    disabled BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,10 @@
+-- links users to accounts at an external identity provider
+CREATE TABLE IF NOT EXISTS user_identities
+(
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user BLOB NOT NULL,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (issuer, subject),
+    FOREIGN KEY(user) REFERENCES users(uuid)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE containers ADD COLUMN parent BLOB REFERENCES containers(uuid);
+
+CREATE INDEX IF NOT EXISTS containers_parent ON containers(parent);
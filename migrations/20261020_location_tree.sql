@@ -0,0 +1,4 @@
+ALTER TABLE locations ADD COLUMN parent BLOB REFERENCES locations(uuid);
+ALTER TABLE locations ADD COLUMN description TEXT;
+
+CREATE INDEX IF NOT EXISTS locations_parent ON locations(parent);
@@ -0,0 +1,45 @@
+CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5
+(
+    kind UNINDEXED,
+    uuid UNINDEXED,
+    name,
+    description,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO search_index (kind, uuid, name, description) SELECT 'item', uuid, name, description FROM items;
+INSERT INTO search_index (kind, uuid, name, description) SELECT 'container', uuid, name, NULL FROM containers;
+INSERT INTO search_index (kind, uuid, name, description) SELECT 'location', uuid, name, description FROM locations;
+
+CREATE TRIGGER IF NOT EXISTS items_search_insert AFTER INSERT ON items BEGIN
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('item', new.uuid, new.name, new.description);
+END;
+CREATE TRIGGER IF NOT EXISTS items_search_update AFTER UPDATE OF name, description ON items BEGIN
+    DELETE FROM search_index WHERE kind = 'item' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('item', new.uuid, new.name, new.description);
+END;
+CREATE TRIGGER IF NOT EXISTS items_search_delete AFTER DELETE ON items BEGIN
+    DELETE FROM search_index WHERE kind = 'item' AND uuid = old.uuid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS containers_search_insert AFTER INSERT ON containers BEGIN
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('container', new.uuid, new.name, NULL);
+END;
+CREATE TRIGGER IF NOT EXISTS containers_search_update AFTER UPDATE OF name ON containers BEGIN
+    DELETE FROM search_index WHERE kind = 'container' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('container', new.uuid, new.name, NULL);
+END;
+CREATE TRIGGER IF NOT EXISTS containers_search_delete AFTER DELETE ON containers BEGIN
+    DELETE FROM search_index WHERE kind = 'container' AND uuid = old.uuid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS locations_search_insert AFTER INSERT ON locations BEGIN
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('location', new.uuid, new.name, new.description);
+END;
+CREATE TRIGGER IF NOT EXISTS locations_search_update AFTER UPDATE OF name, description ON locations BEGIN
+    DELETE FROM search_index WHERE kind = 'location' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) VALUES ('location', new.uuid, new.name, new.description);
+END;
+CREATE TRIGGER IF NOT EXISTS locations_search_delete AFTER DELETE ON locations BEGIN
+    DELETE FROM search_index WHERE kind = 'location' AND uuid = old.uuid;
+END;
@@ -0,0 +1,25 @@
+-- append-only history of all changes to items, containers and locations
+CREATE TABLE IF NOT EXISTS events
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created DATETIME NOT NULL,
+    entity TEXT NOT NULL,
+    entity_id BLOB NOT NULL,
+    kind TEXT NOT NULL,
+    -- JSON array of {field, old, new}
+    changes TEXT NOT NULL,
+    -- no foreign keys, the history outlives what it refers to
+    user BLOB,
+    -- session or API token the change was made with
+    session BLOB
+);
+CREATE INDEX IF NOT EXISTS events_entity ON events (entity_id, id);
+
+CREATE TRIGGER IF NOT EXISTS events_no_update BEFORE UPDATE ON events
+BEGIN
+    SELECT RAISE(ABORT, 'events are append-only');
+END;
+CREATE TRIGGER IF NOT EXISTS events_no_delete BEFORE DELETE ON events
+BEGIN
+    SELECT RAISE(ABORT, 'events are append-only');
+END;
@@ -0,0 +1,62 @@
+-- deleted objects stay in the trash until they're purged after the retention period
+ALTER TABLE items ADD COLUMN deleted_at DATETIME;
+ALTER TABLE containers ADD COLUMN deleted_at DATETIME;
+ALTER TABLE locations ADD COLUMN deleted_at DATETIME;
+
+CREATE INDEX IF NOT EXISTS items_deleted_at ON items(deleted_at);
+CREATE INDEX IF NOT EXISTS containers_deleted_at ON containers(deleted_at);
+CREATE INDEX IF NOT EXISTS locations_deleted_at ON locations(deleted_at);
+
+-- trashed objects can't be found, restored ones are indexed again
+CREATE TRIGGER IF NOT EXISTS items_search_trash AFTER UPDATE OF deleted_at ON items BEGIN
+    DELETE FROM search_index WHERE kind = 'item' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) SELECT 'item', new.uuid, new.name, new.description WHERE new.deleted_at IS NULL;
+END;
+CREATE TRIGGER IF NOT EXISTS containers_search_trash AFTER UPDATE OF deleted_at ON containers BEGIN
+    DELETE FROM search_index WHERE kind = 'container' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) SELECT 'container', new.uuid, new.name, NULL WHERE new.deleted_at IS NULL;
+END;
+CREATE TRIGGER IF NOT EXISTS locations_search_trash AFTER UPDATE OF deleted_at ON locations BEGIN
+    DELETE FROM search_index WHERE kind = 'location' AND uuid = old.uuid;
+    INSERT INTO search_index (kind, uuid, name, description) SELECT 'location', new.uuid, new.name, new.description WHERE new.deleted_at IS NULL;
+END;
+
+-- nothing can be put into a trashed container or at a trashed location
+CREATE TRIGGER IF NOT EXISTS items_trashed_container_insert BEFORE INSERT ON items
+WHEN (SELECT deleted_at FROM containers WHERE uuid = new.container) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'container is in the trash');
+END;
+CREATE TRIGGER IF NOT EXISTS items_trashed_container_update BEFORE UPDATE OF container ON items
+WHEN (SELECT deleted_at FROM containers WHERE uuid = new.container) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'container is in the trash');
+END;
+
+CREATE TRIGGER IF NOT EXISTS containers_trashed_parent_insert BEFORE INSERT ON containers
+WHEN (SELECT deleted_at FROM containers WHERE uuid = new.parent) IS NOT NULL
+    OR (SELECT deleted_at FROM locations WHERE uuid = new.location) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'container or location is in the trash');
+END;
+CREATE TRIGGER IF NOT EXISTS containers_trashed_parent_update BEFORE UPDATE OF parent ON containers
+WHEN (SELECT deleted_at FROM containers WHERE uuid = new.parent) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'container is in the trash');
+END;
+CREATE TRIGGER IF NOT EXISTS containers_trashed_location_update BEFORE UPDATE OF location ON containers
+WHEN (SELECT deleted_at FROM locations WHERE uuid = new.location) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'location is in the trash');
+END;
+
+CREATE TRIGGER IF NOT EXISTS locations_trashed_parent_insert BEFORE INSERT ON locations
+WHEN (SELECT deleted_at FROM locations WHERE uuid = new.parent) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'location is in the trash');
+END;
+CREATE TRIGGER IF NOT EXISTS locations_trashed_parent_update BEFORE UPDATE OF parent ON locations
+WHEN (SELECT deleted_at FROM locations WHERE uuid = new.parent) IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'location is in the trash');
+END;
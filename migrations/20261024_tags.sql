@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS tags
+(
+    uuid BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    -- `#rrggbb`
+    color TEXT
+);
+
+CREATE TABLE IF NOT EXISTS item_tags
+(
+    item BLOB NOT NULL,
+    tag BLOB NOT NULL,
+    PRIMARY KEY (item, tag),
+    FOREIGN KEY(item) REFERENCES items(uuid) ON DELETE CASCADE,
+    FOREIGN KEY(tag) REFERENCES tags(uuid) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS item_tags_tag ON item_tags(tag);
+
+CREATE TABLE IF NOT EXISTS container_tags
+(
+    container BLOB NOT NULL,
+    tag BLOB NOT NULL,
+    PRIMARY KEY (container, tag),
+    FOREIGN KEY(container) REFERENCES containers(uuid) ON DELETE CASCADE,
+    FOREIGN KEY(tag) REFERENCES tags(uuid) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS container_tags_tag ON container_tags(tag);
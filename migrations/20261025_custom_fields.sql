@@ -0,0 +1,38 @@
+-- admin-defined categories of items, each with its own set of custom fields
+CREATE TABLE IF NOT EXISTS categories
+(
+    uuid BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    description TEXT
+);
+
+CREATE TABLE IF NOT EXISTS field_definitions
+(
+    uuid BLOB PRIMARY KEY NOT NULL,
+    category BLOB NOT NULL,
+    name TEXT NOT NULL COLLATE NOCASE,
+    -- text, number, date, boolean, enum or url
+    kind TEXT NOT NULL,
+    -- only for numbers
+    unit TEXT,
+    -- JSON array of the allowed values of enums
+    options TEXT NOT NULL DEFAULT '[]',
+    required BOOLEAN NOT NULL DEFAULT FALSE,
+    UNIQUE (category, name),
+    FOREIGN KEY(category) REFERENCES categories(uuid) ON DELETE CASCADE
+);
+
+ALTER TABLE items ADD COLUMN category BLOB REFERENCES categories(uuid) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS items_category ON items(category);
+
+CREATE TABLE IF NOT EXISTS item_fields
+(
+    item BLOB NOT NULL,
+    field BLOB NOT NULL,
+    -- no declared type, so numbers and booleans are stored and compared as such
+    value NOT NULL,
+    PRIMARY KEY (item, field),
+    FOREIGN KEY(item) REFERENCES items(uuid) ON DELETE CASCADE,
+    FOREIGN KEY(field) REFERENCES field_definitions(uuid) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS item_fields_value ON item_fields(field, value);
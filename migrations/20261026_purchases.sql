@@ -0,0 +1,12 @@
+-- purchase details for insurance and warranty claims
+ALTER TABLE items ADD COLUMN purchased_on DATE;
+ALTER TABLE items ADD COLUMN vendor TEXT;
+-- price of a single piece in hundredths of the currency
+ALTER TABLE items ADD COLUMN price INTEGER;
+-- ISO 4217 code, always set together with the price
+ALTER TABLE items ADD COLUMN currency TEXT;
+ALTER TABLE items ADD COLUMN serial_number TEXT;
+ALTER TABLE items ADD COLUMN model_number TEXT;
+ALTER TABLE items ADD COLUMN warranty_until DATE;
+
+CREATE INDEX IF NOT EXISTS items_warranty_until ON items(warranty_until);
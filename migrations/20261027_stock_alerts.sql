@@ -0,0 +1,15 @@
+-- low-stock thresholds and expiry dates, checked periodically
+ALTER TABLE items ADD COLUMN min_quantity INTEGER;
+ALTER TABLE items ADD COLUMN expires_at DATE;
+CREATE INDEX IF NOT EXISTS items_expires_at ON items(expires_at);
+
+-- alerts that have been sent, so each is only sent once until it no longer applies
+CREATE TABLE IF NOT EXISTS item_alerts
+(
+    item BLOB NOT NULL,
+    -- low_stock, expiring or expired
+    kind TEXT NOT NULL,
+    sent DATETIME NOT NULL,
+    PRIMARY KEY (item, kind),
+    FOREIGN KEY(item) REFERENCES items(uuid) ON DELETE CASCADE
+);
@@ -0,0 +1,19 @@
+-- image galleries of items and containers, the blobs are in the file database
+CREATE TABLE IF NOT EXISTS images
+(
+    uuid BLOB PRIMARY KEY NOT NULL,
+    -- exactly one of item and container is set
+    item BLOB,
+    container BLOB,
+    caption TEXT,
+    position INTEGER NOT NULL,
+    is_primary BOOLEAN NOT NULL DEFAULT FALSE,
+    uploaded DATETIME NOT NULL,
+    uploader BLOB,
+    CHECK ((item IS NULL) != (container IS NULL)),
+    FOREIGN KEY(item) REFERENCES items(uuid) ON DELETE CASCADE,
+    FOREIGN KEY(container) REFERENCES containers(uuid) ON DELETE CASCADE,
+    FOREIGN KEY(uploader) REFERENCES users(uuid) ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS images_item ON images(item, position);
+CREATE INDEX IF NOT EXISTS images_container ON images(container, position);
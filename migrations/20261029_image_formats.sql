@@ -0,0 +1,2 @@
+-- uploads were only accepted as JPEG so far
+ALTER TABLE images ADD COLUMN content_type TEXT NOT NULL DEFAULT 'image/jpeg';
@@ -0,0 +1,2 @@
+-- capture time from the EXIF metadata, in the camera's time
+ALTER TABLE images ADD COLUMN taken_at DATETIME;
@@ -0,0 +1,134 @@
+use anyhow::Error;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{schema::API_TOKEN_TYPE, FileDatabase};
+
+const TOKEN_PREFIX: &str = "hbx_";
+
+/// Metadata of a personal access token. The token itself is only stored as a hash.
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct ApiToken {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user: Uuid,
+    pub label: String,
+    /// Requests authenticated with this token can't modify anything
+    pub read_only: bool,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+fn token_key(token: &str) -> Vec<u8> {
+    std::iter::once(API_TOKEN_TYPE)
+        .chain(Sha256::digest(token.as_bytes()))
+        .collect()
+}
+
+/// Iterates over all stored tokens together with their database key.
+fn all_tokens(db: &FileDatabase) -> impl Iterator<Item = (Box<[u8]>, ApiToken)> + '_ {
+    db.prefix_iterator([API_TOKEN_TYPE])
+        .take_while(|(key, _)| key.first() == Some(&API_TOKEN_TYPE))
+        .filter_map(|(key, value)| {
+            serde_json::from_slice::<ApiToken>(&value)
+                .ok()
+                .map(|token| (key, token))
+        })
+}
+
+/// Creates a new token and returns it in plain text. This is the only time it's available.
+pub fn create(
+    db: &FileDatabase,
+    user: Uuid,
+    label: String,
+    read_only: bool,
+) -> Result<(String, ApiToken), Error> {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let token = secret
+        .iter()
+        .fold(TOKEN_PREFIX.to_owned(), |mut token, byte| {
+            token.push_str(&format!("{:02x}", byte));
+            token
+        });
+    let info = ApiToken {
+        id: Uuid::new_v4(),
+        user,
+        label,
+        read_only,
+        created: Utc::now(),
+        last_used: None,
+    };
+    db.put(token_key(&token), serde_json::to_vec(&info)?)?;
+    Ok((token, info))
+}
+
+pub fn list(db: &FileDatabase, user: Uuid) -> Vec<ApiToken> {
+    all_tokens(db)
+        .filter(|(_, token)| token.user == user)
+        .map(|(_, token)| token)
+        .collect()
+}
+
+/// Looks up a token by id. Only tokens of `user` are considered, unless it's `None`.
+fn find(db: &FileDatabase, id: Uuid, user: Option<Uuid>) -> Option<(Box<[u8]>, ApiToken)> {
+    all_tokens(db).find(|(_, token)| token.id == id && user.is_none_or(|user| token.user == user))
+}
+
+pub fn relabel(
+    db: &FileDatabase,
+    id: Uuid,
+    user: Option<Uuid>,
+    label: String,
+) -> Result<bool, Error> {
+    if let Some((key, mut token)) = find(db, id, user) {
+        token.label = label;
+        db.put(key, serde_json::to_vec(&token)?)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub fn revoke(db: &FileDatabase, id: Uuid, user: Option<Uuid>) -> Result<bool, Error> {
+    if let Some((key, _)) = find(db, id, user) {
+        db.delete(key)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Whether a token hasn't been revoked.
+pub fn exists(db: &FileDatabase, id: Uuid) -> bool {
+    find(db, id, None).is_some()
+}
+
+/// Resolves a token presented by a client, updating its last use time.
+pub fn authenticate(db: &FileDatabase, token: &str) -> Option<ApiToken> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    let key = token_key(token);
+    let mut info = db
+        .get(&key)
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice::<ApiToken>(&value).ok())?;
+    let now = Utc::now();
+    // avoid writing to the database on every single request
+    if info
+        .last_used
+        .is_none_or(|last_used| now - last_used > Duration::minutes(1))
+    {
+        info.last_used = Some(now);
+        if let Ok(value) = serde_json::to_vec(&info) {
+            db.put(key, value).ok();
+        }
+    }
+    Some(info)
+}
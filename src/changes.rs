@@ -0,0 +1,150 @@
+//! Live notifications about changed inventory, served as GraphQL subscriptions.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::{
+    dataloader::Loader,
+    futures_util::{stream, Stream},
+    Enum, SimpleObject,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::{
+    loaders::{ContainerLoader, ItemLoader, LocationLoader},
+    schema::{Container, Item, Location},
+    MetadataDatabase,
+};
+
+/// Changes kept for subscribers that fall behind, older ones are dropped.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    /// Put into another container or location
+    Moved,
+    /// Moved to the trash
+    Deleted,
+    /// Taken back out of the trash
+    Restored,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ItemChanged {
+    pub kind: ChangeKind,
+    pub id: Uuid,
+    /// New state, none if the item was deleted
+    pub item: Option<Item>,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ContainerChanged {
+    pub kind: ChangeKind,
+    pub id: Uuid,
+    /// New state, none if the container was deleted
+    pub container: Option<Container>,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct LocationChanged {
+    pub kind: ChangeKind,
+    pub id: Uuid,
+    /// New state, none if the location was deleted
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Change {
+    Item(ItemChanged),
+    Container(ContainerChanged),
+    Location(LocationChanged),
+}
+
+/// Hands out every change to all current subscribers.
+pub struct Broker {
+    sender: broadcast::Sender<Change>,
+    locations: LocationLoader,
+    containers: ContainerLoader,
+    items: ItemLoader,
+}
+
+impl Broker {
+    pub fn new(db: MetadataDatabase) -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            locations: LocationLoader(db.clone()),
+            containers: ContainerLoader(db.clone()),
+            items: ItemLoader(db),
+        }
+    }
+
+    pub fn changes(&self) -> impl Stream<Item = Change> {
+        stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) => return Some((change, receiver)),
+                    Err(RecvError::Lagged(count)) => {
+                        log::warn!("Subscriber fell behind, skipped {} changes.", count)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    fn send(&self, change: Change) {
+        // fails only if nobody is subscribed
+        self.sender.send(change).ok();
+    }
+
+    /// Looks up the new state of changed objects. A failure here shouldn't fail the change
+    /// itself, which is already committed.
+    async fn current<L>(loader: &L, kind: ChangeKind, ids: &[Uuid]) -> HashMap<Uuid, L::Value>
+    where
+        L: Loader<Uuid, Error = Arc<sqlx::Error>>,
+    {
+        if kind == ChangeKind::Deleted || ids.is_empty() {
+            return HashMap::new();
+        }
+        loader.load(ids).await.unwrap_or_else(|err| {
+            log::error!("Failed fetching changed objects: {}", err);
+            HashMap::new()
+        })
+    }
+
+    pub async fn items(&self, kind: ChangeKind, ids: &[Uuid]) {
+        let mut current = Self::current(&self.items, kind, ids).await;
+        for id in ids {
+            self.send(Change::Item(ItemChanged {
+                kind,
+                id: *id,
+                item: current.remove(id),
+            }));
+        }
+    }
+
+    pub async fn containers(&self, kind: ChangeKind, ids: &[Uuid]) {
+        let mut current = Self::current(&self.containers, kind, ids).await;
+        for id in ids {
+            self.send(Change::Container(ContainerChanged {
+                kind,
+                id: *id,
+                container: current.remove(id),
+            }));
+        }
+    }
+
+    pub async fn locations(&self, kind: ChangeKind, ids: &[Uuid]) {
+        let mut current = Self::current(&self.locations, kind, ids).await;
+        for id in ids {
+            self.send(Change::Location(LocationChanged {
+                kind,
+                id: *id,
+                location: current.remove(id),
+            }));
+        }
+    }
+}
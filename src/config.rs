@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     error::Error,
     fs::read_to_string,
     net::SocketAddr,
@@ -7,6 +8,8 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
+use crate::{image_formats::ImageFormat, users::Role};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Server {
     pub address: Option<SocketAddr>,
@@ -16,12 +19,247 @@ pub struct Server {
 pub struct Database {
     pub file: String,
     pub metadata: String,
+    /// Maximum number of open connections to the metadata database
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+    /// How long to wait for a write lock on the metadata database before failing
+    #[serde(default = "default_busy_timeout_secs")]
+    pub busy_timeout_secs: u64,
+    /// Deleted objects stay in the trash this many days before they're removed for good
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Auth {
-    pub password: String,
+    /// Password of the initial admin account, only used while there are no users
+    pub password: Option<String>,
+    #[serde(default = "default_admin_username")]
+    pub admin_username: String,
     pub cookie_storage: PathBuf,
+    /// Sessions expire after not being used for this many days
+    #[serde(default = "default_session_idle_days")]
+    pub session_idle_days: u32,
+    /// Sessions expire this many days after logging in, no matter whether they're used
+    #[serde(default = "default_session_max_age_days")]
+    pub session_max_age_days: u32,
+    #[serde(default)]
+    pub login_backoff: LoginBackoff,
+    /// Log in through an OpenID Connect identity provider
+    pub oidc: Option<Oidc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Oidc {
+    /// Issuer URL, the discovery document is expected below it
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Public URL of `/login/oidc/callback` on this server
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim holding the user's groups or roles
+    #[serde(default = "default_oidc_roles_claim")]
+    pub roles_claim: String,
+    /// Maps values of the roles claim to roles, the highest one wins
+    #[serde(default)]
+    pub role_mapping: HashMap<String, Role>,
+    /// Role for users without a mapped claim value, those can't log in if unset
+    pub default_role: Option<Role>,
+    /// Link to existing local users whose user name is the verified email address of the login,
+    /// instead of refusing the login
+    #[serde(default)]
+    pub link_existing_users: bool,
+    /// Update the roles of known users from the role mapping on every login. Otherwise it's only
+    /// used for new users, and roles are managed in homebox afterwards.
+    #[serde(default)]
+    pub update_roles: bool,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["profile".to_owned(), "email".to_owned()]
+}
+
+fn default_oidc_roles_claim() -> String {
+    "groups".to_owned()
+}
+
+/// Exponential back-off after failed login attempts
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct LoginBackoff {
+    /// Failed attempts per client address before delays kick in
+    pub free_attempts: u32,
+    /// Failed attempts per account, from any address, before delays kick in for it
+    pub account_free_attempts: u32,
+    /// First delay, doubled with every further failure
+    pub base_delay_secs: u64,
+    /// Upper limit of the delay, counters are reset after this long without failures
+    pub max_delay_secs: u64,
+    /// Failed attempts from any address against any account, against attacks spread over many
+    /// of both. Everybody has to wait once it kicks in, so its delays should be short.
+    pub global: GlobalBackoff,
+}
+
+impl Default for LoginBackoff {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            account_free_attempts: 10,
+            base_delay_secs: 1,
+            max_delay_secs: 60 * 60,
+            global: GlobalBackoff::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GlobalBackoff {
+    /// Failed attempts before delays kick in
+    pub free_attempts: u32,
+    /// First delay, doubled with every further failure
+    pub base_delay_secs: u64,
+    /// Upper limit of the delay, the counter is reset after this long without failures
+    pub max_delay_secs: u64,
+}
+
+impl Default for GlobalBackoff {
+    fn default() -> Self {
+        Self {
+            free_attempts: 100,
+            base_delay_secs: 1,
+            max_delay_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Images {
+    /// Uploads in other formats are converted to this one (jpeg, png or webp), as far as they
+    /// can be decoded
+    pub canonical_format: Option<ImageFormat>,
+    /// Quality of JPEG images written by the server, 1 to 100
+    pub jpeg_quality: u8,
+    /// Thumbnails fitting into squares of these sizes are rendered on upload. Other requested
+    /// sizes are rounded to these and rendered on demand, without being kept.
+    pub thumbnail_sizes: Vec<u32>,
+    /// EXIF, XMP and similar metadata, which tells where and with which device a photo was
+    /// taken, is removed from uploads. Uploads that can't be decoded are refused then.
+    pub strip_metadata: bool,
+    /// `Cache-Control` of images, clients revalidate them by their ETag once they're stale
+    pub cache_control: String,
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Self {
+            canonical_format: None,
+            jpeg_quality: 85,
+            thumbnail_sizes: vec![64, 256],
+            strip_metadata: true,
+            cache_control: "private, max-age=3600".to_owned(),
+        }
+    }
+}
+
+/// Periodic checks for low stock and expiring items
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Notifications {
+    /// At least 1
+    #[serde(default = "default_check_interval_mins")]
+    pub check_interval_mins: u64,
+    /// Items are reported this many days before they expire
+    #[serde(default = "default_expiry_warning_days")]
+    pub expiry_warning_days: u32,
+    /// Every alert is sent to all of these
+    pub sinks: Vec<Sink>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Sink {
+    Email(Email),
+    /// Alerts are posted as JSON
+    Webhook(Webhook),
+    /// Push notifications through ntfy or a compatible server
+    Ntfy(Ntfy),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Email {
+    /// SMTP server
+    pub host: String,
+    /// Defaults to the standard port of the TLS mode
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain text, only meant for local test servers
+    None,
+    #[default]
+    StartTls,
+    Tls,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Webhook {
+    pub url: String,
+    /// Sent along with every request, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ntfy {
+    /// URL of the topic
+    pub url: String,
+    /// Access token for protected topics
+    pub token: Option<String>,
+    /// 1 (min) to 5 (max)
+    pub priority: Option<u8>,
+}
+
+fn default_check_interval_mins() -> u64 {
+    60
+}
+
+fn default_expiry_warning_days() -> u32 {
+    7
+}
+
+fn default_pool_size() -> u32 {
+    8
+}
+
+fn default_busy_timeout_secs() -> u64 {
+    5
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+fn default_admin_username() -> String {
+    "admin".to_owned()
+}
+
+fn default_session_idle_days() -> u32 {
+    30
+}
+
+fn default_session_max_age_days() -> u32 {
+    365
 }
 
 #[derive(Deserialize)]
@@ -30,6 +268,9 @@ pub struct Config {
     pub server: Server,
     pub database: Database,
     pub auth: Auth,
+    #[serde(default)]
+    pub images: Images,
+    pub notifications: Option<Notifications>,
 }
 
 impl Config {
@@ -39,6 +280,19 @@ impl Config {
         let config = read_to_string(path)?;
 
         let config: Config = serde_yaml::from_str(&config)?;
+        if let Some(format @ (ImageFormat::Heic | ImageFormat::Avif)) =
+            config.images.canonical_format
+        {
+            return Err(format!("images can't be converted to {:?}", format).into());
+        }
+        if !(1..=100).contains(&config.images.jpeg_quality) {
+            return Err("images.jpeg_quality must be between 1 and 100".into());
+        }
+        if let Some(notifications) = &config.notifications {
+            if notifications.check_interval_mins == 0 {
+                return Err("notifications.check_interval_mins must be at least 1".into());
+            }
+        }
 
         let config_deserializers = log4rs::config::Deserializers::new();
         let (appenders, mut errors) = config.logging.appenders_lossy(&config_deserializers);
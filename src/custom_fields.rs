@@ -0,0 +1,483 @@
+//! Categories of items, each with its own admin-defined set of typed custom fields.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use async_graphql::{ComplexObject, Context, Enum, InputObject, SimpleObject};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+use crate::MetadataDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FieldKind {
+    Text,
+    /// A number with an optional unit
+    Number,
+    Date,
+    Boolean,
+    /// One of a fixed list of options
+    Enum,
+    Url,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
+pub struct Category {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[ComplexObject]
+impl Category {
+    /// Custom fields of the items in this category
+    async fn fields(&self, ctx: &Context<'_>) -> Result<Vec<FieldDefinition>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        definitions(&mut *db.acquire().await?, Some(self.id)).await
+    }
+}
+
+/// Columns of the `categories` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct CategoryRow {
+    uuid: Vec<u8>,
+    name: String,
+    description: Option<String>,
+}
+
+impl From<CategoryRow> for Category {
+    fn from(row: CategoryRow) -> Self {
+        Category {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            name: row.name,
+            description: row.description,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct FieldDefinition {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub category: Uuid,
+    pub name: String,
+    pub kind: FieldKind,
+    /// Unit of numbers
+    pub unit: Option<String>,
+    /// Allowed values of enums
+    pub options: Vec<String>,
+    /// Items in the category can't be saved without a value
+    pub required: bool,
+}
+
+/// Columns of the `field_definitions` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct FieldRow {
+    uuid: Vec<u8>,
+    category: Vec<u8>,
+    name: String,
+    kind: FieldKind,
+    unit: Option<String>,
+    options: String,
+    required: bool,
+}
+
+impl From<FieldRow> for FieldDefinition {
+    fn from(row: FieldRow) -> Self {
+        FieldDefinition {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            category: Uuid::from_slice(&row.category).unwrap(),
+            name: row.name,
+            kind: row.kind,
+            unit: row.unit,
+            options: serde_json::from_str(&row.options).unwrap_or_default(),
+            required: row.required,
+        }
+    }
+}
+
+/// Value of a custom field of an item. Only the member matching the kind of field is set.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct FieldValue {
+    pub field: FieldDefinition,
+    /// Value of text, enum and URL fields
+    pub text: Option<String>,
+    pub number: Option<f64>,
+    pub date: Option<NaiveDate>,
+    pub boolean: Option<bool>,
+}
+
+/// A field value together with the item it belongs to
+#[derive(sqlx::FromRow)]
+pub(crate) struct FieldValueRow {
+    item: Vec<u8>,
+    value: String,
+    /// Order of definition
+    position: i64,
+    uuid: Vec<u8>,
+    category: Vec<u8>,
+    name: String,
+    kind: FieldKind,
+    unit: Option<String>,
+    options: String,
+    required: bool,
+}
+
+/// Field values by item, in the order the fields were defined.
+pub(crate) fn group(rows: Vec<FieldValueRow>) -> HashMap<Uuid, Vec<FieldValue>> {
+    let mut values: HashMap<Uuid, Vec<(i64, FieldValue)>> = HashMap::new();
+    for row in rows {
+        let item = Uuid::from_slice(&row.item).unwrap();
+        let value = row.value;
+        let field = FieldDefinition::from(FieldRow {
+            uuid: row.uuid,
+            category: row.category,
+            name: row.name,
+            kind: row.kind,
+            unit: row.unit,
+            options: row.options,
+            required: row.required,
+        });
+        let mut result = FieldValue {
+            field,
+            text: None,
+            number: None,
+            date: None,
+            boolean: None,
+        };
+        match result.field.kind {
+            FieldKind::Text | FieldKind::Enum | FieldKind::Url => result.text = Some(value),
+            FieldKind::Number => result.number = value.parse().ok(),
+            FieldKind::Date => result.date = value.parse().ok(),
+            FieldKind::Boolean => result.boolean = Some(value != "0"),
+        }
+        values.entry(item).or_default().push((row.position, result));
+    }
+    values
+        .into_iter()
+        .map(|(item, mut values)| {
+            values.sort_by_key(|(position, _)| *position);
+            (item, values.into_iter().map(|(_, value)| value).collect())
+        })
+        .collect()
+}
+
+/// A new value for a custom field. Leaving all values out removes it.
+#[derive(Debug, InputObject)]
+pub struct FieldInput {
+    pub field: Uuid,
+    /// Value of text, enum and URL fields
+    pub text: Option<String>,
+    pub number: Option<f64>,
+    pub date: Option<NaiveDate>,
+    pub boolean: Option<bool>,
+}
+
+/// A validated value, stored with the matching SQLite type
+#[derive(Debug, PartialEq)]
+enum Stored {
+    Text(String),
+    Number(f64),
+    Date(NaiveDate),
+    Boolean(bool),
+}
+
+impl FieldInput {
+    fn validate(&self, field: &FieldDefinition) -> Result<Option<Stored>, Error> {
+        let given = [
+            self.text.is_some(),
+            self.number.is_some(),
+            self.date.is_some(),
+            self.boolean.is_some(),
+        ];
+        anyhow::ensure!(
+            given.iter().filter(|given| **given).count() <= 1,
+            "Only one value can be given for field `{}`",
+            field.name
+        );
+        if !given.contains(&true) {
+            return Ok(None);
+        }
+        let stored = match field.kind {
+            FieldKind::Text => self.text.clone().map(Stored::Text),
+            FieldKind::Number => self
+                .number
+                .filter(|number| number.is_finite())
+                .map(Stored::Number),
+            FieldKind::Date => self.date.map(Stored::Date),
+            FieldKind::Boolean => self.boolean.map(Stored::Boolean),
+            FieldKind::Enum => self
+                .text
+                .clone()
+                .filter(|text| field.options.contains(text))
+                .map(Stored::Text),
+            FieldKind::Url => self
+                .text
+                .clone()
+                .filter(|text| {
+                    (text.starts_with("http://") || text.starts_with("https://"))
+                        && !text.contains(char::is_whitespace)
+                })
+                .map(Stored::Text),
+        };
+        match stored {
+            Some(stored) => Ok(Some(stored)),
+            None => anyhow::bail!("Invalid value for field `{}`", field.name),
+        }
+    }
+}
+
+/// Checks the parts of a field definition that depend on its kind.
+pub fn check_definition(
+    kind: FieldKind,
+    unit: &Option<String>,
+    options: &[String],
+) -> Result<(), Error> {
+    anyhow::ensure!(
+        unit.is_none() || kind == FieldKind::Number,
+        "Only numbers can have a unit"
+    );
+    anyhow::ensure!(
+        (kind == FieldKind::Enum) == !options.is_empty(),
+        "Enums need options, other fields can't have them"
+    );
+    Ok(())
+}
+
+pub async fn categories(db: &MetadataDatabase) -> Result<Vec<Category>, Error> {
+    let rows = sqlx::query_as!(
+        CategoryRow,
+        "SELECT uuid, name, description FROM categories ORDER BY name COLLATE NOCASE"
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(Category::from).collect())
+}
+
+/// Field definitions of a category in the order they were added, none without a category.
+pub async fn definitions(
+    db: &mut SqliteConnection,
+    category: Option<Uuid>,
+) -> Result<Vec<FieldDefinition>, Error> {
+    let Some(category) = category else {
+        return Ok(Vec::new());
+    };
+    let rows = sqlx::query_as!(
+        FieldRow,
+        r#"SELECT uuid, category, name, kind as "kind: FieldKind", unit, options, required
+        FROM field_definitions WHERE category = ? ORDER BY rowid"#,
+        category
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(FieldDefinition::from).collect())
+}
+
+/// Stores new values of an item's custom fields, which have to belong to its category.
+pub async fn set_values(
+    db: &mut SqliteConnection,
+    item: Uuid,
+    category: Option<Uuid>,
+    values: &[FieldInput],
+) -> Result<(), Error> {
+    let definitions = definitions(&mut *db, category).await?;
+    for input in values {
+        let Some(field) = definitions.iter().find(|field| field.id == input.field) else {
+            anyhow::bail!("The field isn't part of the item's category");
+        };
+        let Some(value) = input.validate(field)? else {
+            sqlx::query!(
+                "DELETE FROM item_fields WHERE item = ? AND field = ?",
+                item,
+                field.id
+            )
+            .execute(&mut *db)
+            .await?;
+            continue;
+        };
+        let query =
+            sqlx::query("INSERT OR REPLACE INTO item_fields (item, field, value) VALUES (?, ?, ?)")
+                .bind(item)
+                .bind(field.id);
+        match value {
+            Stored::Text(text) => query.bind(text),
+            Stored::Number(number) => query.bind(number),
+            Stored::Date(date) => query.bind(date),
+            Stored::Boolean(boolean) => query.bind(boolean),
+        }
+        .execute(&mut *db)
+        .await?;
+    }
+    for field in definitions.iter().filter(|field| field.required) {
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM item_fields WHERE item = ? AND field = ?",
+            item,
+            field.id
+        )
+        .fetch_one(&mut *db)
+        .await?;
+        anyhow::ensure!(count > 0, "Field `{}` is required", field.name);
+    }
+    Ok(())
+}
+
+/// Drops values of fields that aren't part of the item's current category.
+pub async fn drop_foreign_values(db: &mut SqliteConnection, item: Uuid) -> Result<(), Error> {
+    sqlx::query!(
+        "DELETE FROM item_fields WHERE item = ?1 AND field NOT IN (SELECT f.uuid FROM field_definitions f JOIN items i ON i.category = f.category WHERE i.uuid = ?1)",
+        item
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(kind: FieldKind) -> FieldDefinition {
+        FieldDefinition {
+            id: Uuid::new_v4(),
+            category: Uuid::new_v4(),
+            name: "field".to_owned(),
+            kind,
+            unit: None,
+            options: if kind == FieldKind::Enum {
+                vec!["red".to_owned(), "green".to_owned()]
+            } else {
+                Vec::new()
+            },
+            required: false,
+        }
+    }
+
+    fn input(field: &FieldDefinition) -> FieldInput {
+        FieldInput {
+            field: field.id,
+            text: None,
+            number: None,
+            date: None,
+            boolean: None,
+        }
+    }
+
+    fn text(field: &FieldDefinition, text: &str) -> FieldInput {
+        FieldInput {
+            text: Some(text.to_owned()),
+            ..input(field)
+        }
+    }
+
+    #[test]
+    fn no_value_removes_it() {
+        for kind in [FieldKind::Text, FieldKind::Number, FieldKind::Enum] {
+            let field = field(kind);
+            assert_eq!(input(&field).validate(&field).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn accepts_values_matching_the_kind() {
+        let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let text_field = field(FieldKind::Text);
+        let number = field(FieldKind::Number);
+        let date_field = field(FieldKind::Date);
+        let boolean = field(FieldKind::Boolean);
+        assert_eq!(
+            text(&text_field, "anything").validate(&text_field).unwrap(),
+            Some(Stored::Text("anything".to_owned()))
+        );
+        let value = FieldInput {
+            number: Some(2.5),
+            ..input(&number)
+        };
+        assert_eq!(value.validate(&number).unwrap(), Some(Stored::Number(2.5)));
+        let value = FieldInput {
+            date: Some(date),
+            ..input(&date_field)
+        };
+        assert_eq!(
+            value.validate(&date_field).unwrap(),
+            Some(Stored::Date(date))
+        );
+        let value = FieldInput {
+            boolean: Some(false),
+            ..input(&boolean)
+        };
+        assert_eq!(
+            value.validate(&boolean).unwrap(),
+            Some(Stored::Boolean(false))
+        );
+    }
+
+    #[test]
+    fn rejects_values_of_another_kind() {
+        let number = field(FieldKind::Number);
+        assert!(text(&number, "3").validate(&number).is_err());
+        let boolean = field(FieldKind::Boolean);
+        let value = FieldInput {
+            number: Some(1.0),
+            ..input(&boolean)
+        };
+        assert!(value.validate(&boolean).is_err());
+    }
+
+    #[test]
+    fn rejects_several_values() {
+        let text_field = field(FieldKind::Text);
+        let value = FieldInput {
+            number: Some(1.0),
+            ..text(&text_field, "one")
+        };
+        let err = value.validate(&text_field).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Only one value can be given for field `field`"
+        );
+    }
+
+    #[test]
+    fn numbers_have_to_be_finite() {
+        let number = field(FieldKind::Number);
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let value = FieldInput {
+                number: Some(value),
+                ..input(&number)
+            };
+            assert!(value.validate(&number).is_err());
+        }
+    }
+
+    #[test]
+    fn enums_only_take_their_options() {
+        let color = field(FieldKind::Enum);
+        assert_eq!(
+            text(&color, "green").validate(&color).unwrap(),
+            Some(Stored::Text("green".to_owned()))
+        );
+        assert!(text(&color, "blue").validate(&color).is_err());
+        assert!(text(&color, "Green").validate(&color).is_err());
+    }
+
+    #[test]
+    fn urls_need_an_http_scheme_and_no_spaces() {
+        let url = field(FieldKind::Url);
+        for valid in ["http://example.com", "https://example.com/a?b=c"] {
+            assert!(text(&url, valid).validate(&url).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "example.com",
+            "ftp://example.com",
+            "javascript:alert(1)",
+            "https://example.com/a b",
+        ] {
+            assert!(text(&url, invalid).validate(&url).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Image galleries of items and containers. The metadata is kept in the `images` table, the
+//! blobs in the file database under the image's own id.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use async_graphql::{ComplexObject, SimpleObject};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocksdb::WriteBatch;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{QueryBuilder, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{
+    changes::ChangeKind,
+    history::{Entity, Tracker},
+    http_cache,
+    image_formats::Upload,
+    schema::{CONTAINER_IMAGE_TYPE, IMAGE_TYPE, ITEM_IMAGE_TYPE},
+    thumbnails,
+    user_session::Credential,
+    FileDatabase, MetadataDatabase,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
+pub struct Image {
+    pub id: Uuid,
+    /// Where the image itself can be fetched
+    pub url: String,
+    pub content_type: String,
+    pub caption: Option<String>,
+    /// Images are shown in ascending order
+    pub position: i64,
+    /// Shown wherever there's only room for one image
+    pub primary: bool,
+    pub uploaded: DateTime<Utc>,
+    /// Name of the user who uploaded it
+    pub uploaded_by: Option<String>,
+    /// When the photo was taken according to its metadata, in the camera's time
+    pub taken_at: Option<NaiveDateTime>,
+}
+
+#[ComplexObject]
+impl Image {
+    /// Where the image can be fetched scaled down to fit into a square of this size, which is
+    /// rounded up to one of the configured thumbnail sizes
+    async fn thumbnail_url(&self, size: u32) -> String {
+        self.sized_url(size)
+    }
+}
+
+impl Image {
+    pub fn sized_url(&self, size: u32) -> String {
+        format!("{}?w={}&h={}", self.url, size, size)
+    }
+}
+
+/// Columns of the `images` table, along with the item or container and the uploader's name
+#[derive(sqlx::FromRow)]
+pub(crate) struct ImageRow {
+    uuid: Vec<u8>,
+    owner: Vec<u8>,
+    content_type: String,
+    caption: Option<String>,
+    position: i64,
+    is_primary: bool,
+    uploaded: NaiveDateTime,
+    uploaded_by: Option<String>,
+    taken_at: Option<NaiveDateTime>,
+}
+
+impl From<ImageRow> for Image {
+    fn from(row: ImageRow) -> Self {
+        let id = Uuid::from_slice(&row.uuid).unwrap();
+        Image {
+            id,
+            url: format!("/image/{}", id),
+            content_type: row.content_type,
+            caption: row.caption,
+            position: row.position,
+            primary: row.is_primary,
+            uploaded: DateTime::from_utc(row.uploaded, Utc),
+            uploaded_by: row.uploaded_by,
+            taken_at: row.taken_at,
+        }
+    }
+}
+
+/// Images by their item or container, in order.
+pub(crate) fn group(rows: Vec<ImageRow>) -> HashMap<Uuid, Vec<Image>> {
+    let mut images: HashMap<Uuid, Vec<Image>> = HashMap::new();
+    for row in rows {
+        images
+            .entry(Uuid::from_slice(&row.owner).unwrap())
+            .or_default()
+            .push(Image::from(row));
+    }
+    for images in images.values_mut() {
+        images.sort_by_key(|image| image.position);
+    }
+    images
+}
+
+/// The object an image belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Item(Uuid),
+    Container(Uuid),
+}
+
+impl Owner {
+    pub fn id(self) -> Uuid {
+        match self {
+            Owner::Item(id) | Owner::Container(id) => id,
+        }
+    }
+
+    fn entity(self) -> Entity {
+        match self {
+            Owner::Item(_) => Entity::Item,
+            Owner::Container(_) => Entity::Container,
+        }
+    }
+
+    /// Table of the owning objects and the column of `images` referring to them
+    fn tables(self) -> (&'static str, &'static str) {
+        match self {
+            Owner::Item(_) => ("items", "item"),
+            Owner::Container(_) => ("containers", "container"),
+        }
+    }
+
+    fn from_columns(item: Option<Vec<u8>>, container: Option<Vec<u8>>) -> Result<Self, Error> {
+        match (item, container) {
+            (Some(item), _) => Ok(Owner::Item(Uuid::from_slice(&item)?)),
+            (_, Some(container)) => Ok(Owner::Container(Uuid::from_slice(&container)?)),
+            _ => anyhow::bail!("Image without an owner"),
+        }
+    }
+}
+
+pub fn image_key(id: Uuid) -> Vec<u8> {
+    std::iter::once(IMAGE_TYPE)
+        .chain(id.as_bytes().iter().copied())
+        .collect()
+}
+
+/// Whether an item or container exists and isn't in the trash.
+async fn owner_exists(db: &mut SqliteConnection, owner: Owner) -> Result<bool, Error> {
+    let (objects, _) = owner.tables();
+    let mut exists = QueryBuilder::new(format!(
+        "SELECT COUNT(*) > 0 FROM {} WHERE deleted_at IS NULL AND uuid = ",
+        objects
+    ));
+    exists.push_bind(owner.id());
+    let (exists,): (bool,) = exists.build_query_as().fetch_one(db).await?;
+    Ok(exists)
+}
+
+async fn owner_of(db: &mut SqliteConnection, id: Uuid) -> Result<Option<Owner>, Error> {
+    sqlx::query!("SELECT item, container FROM images WHERE uuid = ?", id)
+        .fetch_optional(db)
+        .await?
+        .map(|row| Owner::from_columns(row.item, row.container))
+        .transpose()
+}
+
+/// Images of an item or container, in order.
+pub async fn list(db: &mut SqliteConnection, owner: Owner) -> Result<Vec<Image>, Error> {
+    let (_, column) = owner.tables();
+    let mut query = QueryBuilder::new(format!(
+        "SELECT i.uuid, i.{} as owner, i.content_type, i.caption, i.position, i.is_primary, i.uploaded, u.username as uploaded_by, i.taken_at
+        FROM images i LEFT JOIN users u ON u.uuid = i.uploader WHERE i.{} = ",
+        column, column
+    ));
+    query.push_bind(owner.id()).push(" ORDER BY i.position");
+    let rows: Vec<ImageRow> = query.build_query_as().fetch_all(db).await?;
+    Ok(rows.into_iter().map(Image::from).collect())
+}
+
+/// The primary image of an item or container, `None` if its gallery is empty.
+pub async fn primary(db: &MetadataDatabase, owner: Owner) -> Result<Option<Image>, Error> {
+    let mut db = db.acquire().await?;
+    Ok(list(&mut db, owner)
+        .await?
+        .into_iter()
+        .find(|image| image.primary))
+}
+
+/// Adds an image at the end of the gallery, the first one becomes the primary image. Returns
+/// `None` if the item or container doesn't exist or is in the trash.
+pub async fn add(
+    files: &FileDatabase,
+    db: &MetadataDatabase,
+    owner: Owner,
+    uploader: Uuid,
+    credential: Credential,
+    caption: Option<String>,
+    upload: &Upload,
+) -> Result<Option<Image>, Error> {
+    let mut tx = db.begin().await?;
+    if !owner_exists(&mut tx, owner).await? {
+        return Ok(None);
+    }
+    let tracker = Tracker::new(&mut tx, owner.entity(), &[owner.id()]).await?;
+    let (_, column) = owner.tables();
+    let mut next = QueryBuilder::new(format!(
+        "SELECT COALESCE(MAX(position) + 1, 0), COUNT(*) = 0 FROM images WHERE {} = ",
+        column
+    ));
+    next.push_bind(owner.id());
+    let (position, primary): (i64, bool) = next.build_query_as().fetch_one(&mut tx).await?;
+
+    let uuid = Uuid::new_v4();
+    let mut insert = QueryBuilder::new(format!(
+        "INSERT INTO images (uuid, {}, content_type, caption, position, is_primary, uploaded, uploader, taken_at) ",
+        column
+    ));
+    insert.push_values([()], |mut row, _| {
+        row.push_bind(uuid)
+            .push_bind(owner.id())
+            .push_bind(upload.format.content_type())
+            .push_bind(caption.clone())
+            .push_bind(position)
+            .push_bind(primary)
+            .push_bind(Utc::now())
+            .push_bind(uploader)
+            .push_bind(upload.taken_at);
+    });
+    insert.build().execute(&mut tx).await?;
+    let image = list(&mut tx, owner)
+        .await?
+        .into_iter()
+        .find(|image| image.id == uuid);
+    tracker
+        .record_by(
+            &mut tx,
+            Some(uploader),
+            Some(credential),
+            ChangeKind::Updated,
+        )
+        .await?;
+    // a blob without metadata is harmless, metadata without a blob isn't
+    http_cache::store(files, &image_key(uuid), &upload.data)?;
+    tx.commit().await?;
+    Ok(image)
+}
+
+/// Type of a stored image, `None` if it doesn't exist.
+pub async fn content_type(db: &MetadataDatabase, id: Uuid) -> Result<Option<String>, Error> {
+    Ok(
+        sqlx::query_scalar!("SELECT content_type FROM images WHERE uuid = ?", id)
+            .fetch_optional(db)
+            .await?,
+    )
+}
+
+/// Changes the caption of an image and possibly makes it the primary one. Returns what it
+/// belongs to, or `None` if it doesn't exist.
+pub async fn update(
+    db: &MetadataDatabase,
+    user: Uuid,
+    credential: Credential,
+    id: Uuid,
+    caption: Option<String>,
+    primary: bool,
+) -> Result<Option<Owner>, Error> {
+    let mut tx = db.begin().await?;
+    let Some(owner) = owner_of(&mut tx, id).await? else {
+        return Ok(None);
+    };
+    let tracker = Tracker::new(&mut tx, owner.entity(), &[owner.id()]).await?;
+    sqlx::query!("UPDATE images SET caption = ? WHERE uuid = ?", caption, id)
+        .execute(&mut tx)
+        .await?;
+    if primary {
+        let (_, column) = owner.tables();
+        let mut query = QueryBuilder::new("UPDATE images SET is_primary = (uuid = ");
+        query
+            .push_bind(id)
+            .push(format!(") WHERE {} = ", column))
+            .push_bind(owner.id());
+        query.build().execute(&mut tx).await?;
+    }
+    tracker
+        .record_by(&mut tx, Some(user), Some(credential), ChangeKind::Updated)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(owner))
+}
+
+/// Puts the given images first, in that order. The others keep their relative order after
+/// them. Returns whether the item or container exists.
+pub async fn reorder(
+    db: &MetadataDatabase,
+    user: Uuid,
+    credential: Credential,
+    owner: Owner,
+    order: &[Uuid],
+) -> Result<bool, Error> {
+    let mut tx = db.begin().await?;
+    if !owner_exists(&mut tx, owner).await? {
+        return Ok(false);
+    }
+    let tracker = Tracker::new(&mut tx, owner.entity(), &[owner.id()]).await?;
+    let current: Vec<Uuid> = list(&mut tx, owner)
+        .await?
+        .into_iter()
+        .map(|image| image.id)
+        .collect();
+    let ordered = order
+        .iter()
+        .filter(|id| current.contains(id))
+        .chain(current.iter().filter(|id| !order.contains(id)));
+    for (position, id) in ordered.enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "UPDATE images SET position = ? WHERE uuid = ?",
+            position,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tracker
+        .record_by(&mut tx, Some(user), Some(credential), ChangeKind::Updated)
+        .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Deletes an image, the next one becomes primary if it was. Returns what it belonged to, or
+/// `None` if it doesn't exist.
+pub async fn delete(
+    files: &FileDatabase,
+    db: &MetadataDatabase,
+    user: Uuid,
+    credential: Credential,
+    id: Uuid,
+) -> Result<Option<Owner>, Error> {
+    let mut tx = db.begin().await?;
+    let Some(owner) = owner_of(&mut tx, id).await? else {
+        return Ok(None);
+    };
+    let tracker = Tracker::new(&mut tx, owner.entity(), &[owner.id()]).await?;
+    sqlx::query!("DELETE FROM images WHERE uuid = ?", id)
+        .execute(&mut tx)
+        .await?;
+    let (_, column) = owner.tables();
+    let mut query = QueryBuilder::new(format!(
+        "UPDATE images SET is_primary = TRUE WHERE uuid = (SELECT uuid FROM images WHERE {} = ",
+        column
+    ));
+    query
+        .push_bind(owner.id())
+        .push(" ORDER BY position LIMIT 1) AND NOT EXISTS (SELECT 1 FROM images WHERE is_primary AND ")
+        .push(column)
+        .push(" = ")
+        .push_bind(owner.id())
+        .push(")");
+    query.build().execute(&mut tx).await?;
+    tracker
+        .record_by(&mut tx, Some(user), Some(credential), ChangeKind::Updated)
+        .await?;
+    tx.commit().await?;
+    let key = image_key(id);
+    http_cache::remove(files, &key)?;
+    thumbnails::delete_variants(files, &key)?;
+    Ok(Some(owner))
+}
+
+/// Removes the blobs of images whose metadata is gone, along with their variants.
+pub fn delete_blobs(files: &FileDatabase, images: &[Uuid]) -> Result<(), Error> {
+    let mut batch = WriteBatch::default();
+    for image in images {
+        let key = image_key(*image);
+        thumbnails::delete_variants(files, &key)?;
+        http_cache::delete(&mut batch, &key);
+    }
+    files.write(batch)?;
+    Ok(())
+}
+
+/// Turns the single images of items and containers from before galleries existed into gallery
+/// images. Ids are derived from the old keys, so an interrupted run is simply repeated.
+pub async fn convert_legacy_images(
+    files: &FileDatabase,
+    db: &MetadataDatabase,
+) -> Result<usize, Error> {
+    let mut legacy = Vec::new();
+    for prefix in [CONTAINER_IMAGE_TYPE, ITEM_IMAGE_TYPE] {
+        for (key, data) in files
+            .prefix_iterator([prefix])
+            .take_while(|(key, _)| key.first() == Some(&prefix))
+        {
+            // container images are keyed by container, item images by container and item
+            let owner = match (prefix, key.len()) {
+                (CONTAINER_IMAGE_TYPE, 17) => Owner::Container(Uuid::from_slice(&key[1..])?),
+                (ITEM_IMAGE_TYPE, 33) => Owner::Item(Uuid::from_slice(&key[17..])?),
+                _ => continue,
+            };
+            let hash = Sha256::digest(&key);
+            let id = Uuid::from_slice(&hash[..16])?;
+            legacy.push((key, data, owner, id));
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(0);
+    }
+
+    let mut batch = WriteBatch::default();
+    for (_, data, _, id) in &legacy {
+        http_cache::put(&mut batch, &image_key(*id), data)?;
+    }
+    files.write(batch)?;
+    let mut tx = db.begin().await?;
+    let now = Utc::now();
+    // images of objects that have been purged are dropped
+    let mut orphans = Vec::new();
+    for (_, _, owner, id) in &legacy {
+        let (objects, column) = owner.tables();
+        let mut insert = QueryBuilder::new(format!(
+            "INSERT OR IGNORE INTO images (uuid, {}, position, is_primary, uploaded) SELECT ",
+            column
+        ));
+        insert
+            .push_bind(*id)
+            .push(", uuid, 0, TRUE, ")
+            .push_bind(now)
+            .push(format!(" FROM {} WHERE uuid = ", objects))
+            .push_bind(owner.id());
+        insert.build().execute(&mut tx).await?;
+        if sqlx::query_scalar!("SELECT COUNT(*) FROM images WHERE uuid = ?", id)
+            .fetch_one(&mut tx)
+            .await?
+            == 0
+        {
+            orphans.push(*id);
+        }
+    }
+    tx.commit().await?;
+    let mut batch = WriteBatch::default();
+    for (key, _, _, _) in &legacy {
+        batch.delete(key);
+    }
+    for id in &orphans {
+        http_cache::delete(&mut batch, &image_key(*id));
+    }
+    files.write(batch)?;
+    Ok(legacy.len() - orphans.len())
+}
@@ -0,0 +1,393 @@
+//! Append-only log of every change to items, containers and locations, with the values before
+//! and after and who made it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Error;
+use async_graphql::{Context, Enum, Json, SimpleObject};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{QueryBuilder, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{
+    changes::ChangeKind,
+    custom_fields::{self, FieldValue, FieldValueRow},
+    gallery::{self, Image, ImageRow},
+    http_cache,
+    listing::MAX_PAGE_SIZE,
+    loaders::{
+        self, CONTAINERS, CONTAINER_IMAGES, CONTAINER_TAGS, ITEMS, ITEM_FIELDS, ITEM_IMAGES,
+        ITEM_TAGS, LOCATIONS,
+    },
+    schema::{location_image_key, Container, ContainerRow, Item, ItemRow, Location, LocationRow},
+    tags::{self, Tag, TaggedRow},
+    user_session::Credential,
+    users::User,
+    FileDatabase, MetadataDatabase,
+};
+
+/// Columns of events, with the name of the user that made the change
+pub const EVENT_COLUMNS: &str = "SELECT e.id, e.created, e.entity, e.entity_id, e.kind, e.changes, e.user, u.username, e.session";
+pub const EVENT_TABLES: &str = " FROM events e LEFT JOIN users u ON u.uuid = e.user";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Entity {
+    Item,
+    Container,
+    Location,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct FieldChange {
+    pub field: String,
+    /// Value before the change, null for new objects
+    pub old: Json<Value>,
+    /// Value after the change, null for deleted objects
+    pub new: Json<Value>,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Event {
+    pub id: i64,
+    pub created: DateTime<Utc>,
+    pub entity: Entity,
+    pub entity_id: Uuid,
+    pub kind: ChangeKind,
+    pub changes: Vec<FieldChange>,
+    /// User that made the change
+    pub user: Option<Uuid>,
+    pub username: Option<String>,
+    /// Session or API token the change was made with
+    pub session: Option<Uuid>,
+}
+
+/// Columns of `EVENT_COLUMNS`
+#[derive(sqlx::FromRow)]
+pub struct EventRow {
+    id: i64,
+    created: NaiveDateTime,
+    entity: Entity,
+    entity_id: Vec<u8>,
+    kind: ChangeKind,
+    changes: String,
+    user: Option<Vec<u8>>,
+    username: Option<String>,
+    session: Option<Vec<u8>>,
+}
+
+impl From<EventRow> for Event {
+    fn from(row: EventRow) -> Self {
+        Event {
+            id: row.id,
+            created: DateTime::from_utc(row.created, Utc),
+            entity: row.entity,
+            entity_id: Uuid::from_slice(&row.entity_id).unwrap(),
+            kind: row.kind,
+            changes: serde_json::from_str(&row.changes).unwrap_or_default(),
+            user: row.user.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+            username: row.username,
+            session: row.session.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+        }
+    }
+}
+
+/// Tracked fields of an object, always in the same order.
+type Snapshot = Vec<(&'static str, Value)>;
+
+fn item_snapshot(item: Item) -> (Uuid, Snapshot) {
+    (
+        item.id,
+        vec![
+            ("name", json!(item.name)),
+            ("description", json!(item.description)),
+            ("quantity", json!(item.quantity)),
+            ("min_quantity", json!(item.min_quantity)),
+            ("expires_at", json!(item.expires_at)),
+            ("container", json!(item.container_id)),
+            ("category", json!(item.category_id)),
+            ("purchase", json!(item.purchase)),
+        ],
+    )
+}
+
+fn container_snapshot(container: Container) -> (Uuid, Snapshot) {
+    (
+        container.id,
+        vec![
+            ("name", json!(container.name)),
+            ("location", json!(container.location_id)),
+            ("parent", json!(container.parent_id)),
+        ],
+    )
+}
+
+fn location_snapshot(location: Location) -> (Uuid, Snapshot) {
+    (
+        location.id,
+        vec![
+            ("name", json!(location.name)),
+            ("description", json!(location.description)),
+            ("parent", json!(location.parent_id)),
+        ],
+    )
+}
+
+/// Names of the tags, sorted so that only attaching and detaching counts as a change
+fn tag_names(tags: &[Tag]) -> Value {
+    let mut names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+    names.sort_unstable();
+    json!(names)
+}
+
+/// Custom field values by field name
+fn field_values(values: &[FieldValue]) -> Value {
+    values
+        .iter()
+        .map(|value| {
+            let json = [
+                json!(value.text),
+                json!(value.number),
+                json!(value.date),
+                json!(value.boolean),
+            ]
+            .into_iter()
+            .find(|json| !json.is_null())
+            .unwrap_or_default();
+            (value.field.name.clone(), json)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Images in the order they're shown
+fn gallery_images(images: &[Image]) -> Value {
+    images
+        .iter()
+        .map(|image| json!({"id": image.id, "caption": image.caption, "primary": image.primary}))
+        .collect()
+}
+
+/// Adds a field made of objects related to the snapshots, by the id of the snapshot.
+fn add_related<T>(
+    snapshots: &mut HashMap<Uuid, Snapshot>,
+    field: &'static str,
+    related: HashMap<Uuid, Vec<T>>,
+    value: fn(&[T]) -> Value,
+) {
+    for (id, snapshot) in snapshots {
+        let related = related.get(id).map_or(&[][..], Vec::as_slice);
+        snapshot.push((field, value(related)));
+    }
+}
+
+/// Adds the content hash of each location's floor plan, null for ones without.
+fn add_floor_plans(
+    snapshots: &mut HashMap<Uuid, Snapshot>,
+    files: &FileDatabase,
+) -> Result<(), Error> {
+    for (id, snapshot) in snapshots {
+        let floor_plan =
+            http_cache::load(files, &location_image_key(*id))?.map(|(_, info)| info.etag);
+        snapshot.push(("floor_plan", json!(floor_plan)));
+    }
+    Ok(())
+}
+
+async fn snapshots(
+    db: &mut SqliteConnection,
+    entity: Entity,
+    ids: &[Uuid],
+) -> Result<HashMap<Uuid, Snapshot>, Error> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(match entity {
+        Entity::Item => {
+            let mut snapshots: HashMap<_, _> =
+                loaders::fetch_all::<ItemRow, _>(&mut *db, ITEMS, ids)
+                    .await?
+                    .into_iter()
+                    .map(|row| item_snapshot(row.into()))
+                    .collect();
+            let tags = loaders::fetch_all::<TaggedRow, _>(&mut *db, ITEM_TAGS, ids).await?;
+            add_related(&mut snapshots, "tags", tags::group(tags), tag_names);
+            let fields = loaders::fetch_all::<FieldValueRow, _>(&mut *db, ITEM_FIELDS, ids).await?;
+            let fields = custom_fields::group(fields);
+            add_related(&mut snapshots, "fields", fields, field_values);
+            let images = loaders::fetch_all::<ImageRow, _>(&mut *db, ITEM_IMAGES, ids).await?;
+            add_related(
+                &mut snapshots,
+                "images",
+                gallery::group(images),
+                gallery_images,
+            );
+            snapshots
+        }
+        Entity::Container => {
+            let mut snapshots: HashMap<_, _> =
+                loaders::fetch_all::<ContainerRow, _>(&mut *db, CONTAINERS, ids)
+                    .await?
+                    .into_iter()
+                    .map(|row| container_snapshot(row.into()))
+                    .collect();
+            let tags = loaders::fetch_all::<TaggedRow, _>(&mut *db, CONTAINER_TAGS, ids).await?;
+            add_related(&mut snapshots, "tags", tags::group(tags), tag_names);
+            let images = loaders::fetch_all::<ImageRow, _>(&mut *db, CONTAINER_IMAGES, ids).await?;
+            add_related(
+                &mut snapshots,
+                "images",
+                gallery::group(images),
+                gallery_images,
+            );
+            snapshots
+        }
+        Entity::Location => loaders::fetch_all::<LocationRow, _>(db, LOCATIONS, ids)
+            .await?
+            .into_iter()
+            .map(|row| location_snapshot(row.into()))
+            .collect(),
+    })
+}
+
+fn diff(before: Option<&Snapshot>, after: Option<&Snapshot>) -> Vec<FieldChange> {
+    let value = |snapshot: Option<&Snapshot>, field: &str| {
+        snapshot
+            .and_then(|snapshot| snapshot.iter().find(|(name, _)| *name == field))
+            .map_or(Value::Null, |(_, value)| value.clone())
+    };
+    before
+        .or(after)
+        .into_iter()
+        .flatten()
+        .filter_map(|(field, _)| {
+            let old = value(before, field);
+            let new = value(after, field);
+            (old != new).then(|| FieldChange {
+                field: (*field).to_owned(),
+                old: Json(old),
+                new: Json(new),
+            })
+        })
+        .collect()
+}
+
+/// Remembers the state of objects before a change, to record what it changed afterwards.
+pub struct Tracker {
+    entity: Entity,
+    ids: Vec<Uuid>,
+    /// Where the floor plans are, if they're tracked along with the locations
+    files: Option<Arc<FileDatabase>>,
+    before: HashMap<Uuid, Snapshot>,
+}
+
+impl Tracker {
+    /// Takes a snapshot of the given objects. Ones that don't exist yet or are in the trash
+    /// count as missing.
+    pub async fn new(
+        db: &mut SqliteConnection,
+        entity: Entity,
+        ids: &[Uuid],
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            entity,
+            ids: ids.to_vec(),
+            files: None,
+            before: snapshots(db, entity, ids).await?,
+        })
+    }
+
+    /// Like `new` for locations, also tracking their floor plans in the file database.
+    pub async fn with_floor_plans(
+        db: &mut SqliteConnection,
+        files: Arc<FileDatabase>,
+        ids: &[Uuid],
+    ) -> Result<Self, Error> {
+        let mut before = snapshots(db, Entity::Location, ids).await?;
+        add_floor_plans(&mut before, &files)?;
+        Ok(Self {
+            entity: Entity::Location,
+            ids: ids.to_vec(),
+            files: Some(files),
+            before,
+        })
+    }
+
+    /// Records an event for every tracked object that changed, as part of the same transaction.
+    /// Objects that are gone are recorded as deleted, new ones as created unless they were
+    /// restored, the others as `kind`. Returns the ones that actually changed.
+    pub async fn record(
+        self,
+        db: &mut SqliteConnection,
+        ctx: &Context<'_>,
+        kind: ChangeKind,
+    ) -> Result<Vec<Uuid>, Error> {
+        let user = ctx.data_opt::<User>().map(|user| user.id);
+        let credential = ctx.data_opt::<Credential>().copied();
+        self.record_by(db, user, credential, kind).await
+    }
+
+    /// Like `record`, for changes made outside of GraphQL by the given user and credential.
+    pub async fn record_by(
+        self,
+        db: &mut SqliteConnection,
+        user: Option<Uuid>,
+        credential: Option<Credential>,
+        kind: ChangeKind,
+    ) -> Result<Vec<Uuid>, Error> {
+        let mut after = snapshots(db, self.entity, &self.ids).await?;
+        if let Some(files) = &self.files {
+            add_floor_plans(&mut after, files)?;
+        }
+        let now = Utc::now();
+        let session = credential.map(Credential::id);
+        let mut changed = Vec::new();
+        for id in self.ids {
+            let before = self.before.get(&id);
+            let after = after.get(&id);
+            let kind = match (before, after) {
+                (None, None) => continue,
+                (None, Some(_)) if kind == ChangeKind::Restored => kind,
+                (None, Some(_)) => ChangeKind::Created,
+                (Some(_), None) => ChangeKind::Deleted,
+                (Some(_), Some(_)) => kind,
+            };
+            let changes = diff(before, after);
+            if changes.is_empty() && kind != ChangeKind::Created {
+                continue;
+            }
+            let changes = serde_json::to_string(&changes)?;
+            sqlx::query!(
+                "INSERT INTO events (created, entity, entity_id, kind, changes, user, session) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                now,
+                self.entity,
+                id,
+                kind,
+                changes,
+                user,
+                session
+            )
+            .execute(&mut *db)
+            .await?;
+            changed.push(id);
+        }
+        Ok(changed)
+    }
+}
+
+/// Latest events of one object, newest first. At most `MAX_PAGE_SIZE` are returned.
+pub async fn of_entity(db: &MetadataDatabase, id: Uuid, limit: usize) -> Result<Vec<Event>, Error> {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let mut query = QueryBuilder::new(EVENT_COLUMNS);
+    query
+        .push(EVENT_TABLES)
+        .push(" WHERE e.entity_id = ")
+        .push_bind(id)
+        .push(" ORDER BY e.id DESC LIMIT ")
+        .push_bind(limit as i64);
+    let rows: Vec<EventRow> = query.build_query_as().fetch_all(db).await?;
+    Ok(rows.into_iter().map(Event::from).collect())
+}
@@ -0,0 +1,347 @@
+//! HTTP caching of the images in the file database. Every blob has its content hash and the time
+//! it was written stored next to it, which answer conditional and range requests.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    http::header::{
+        self, AcceptRanges, ContentRange, ContentRangeSpec, ETag, EntityTag, IfModifiedSince,
+        IfNoneMatch, IfRange, LastModified, Range, RangeUnit,
+    },
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use rocksdb::WriteBatch;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Images, schema::BLOB_INFO_TYPE, FileDatabase};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobInfo {
+    /// Hex encoded SHA-256 of the content
+    pub etag: String,
+    pub modified: DateTime<Utc>,
+}
+
+impl BlobInfo {
+    pub fn new(data: &[u8]) -> Self {
+        let etag = Sha256::digest(data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        BlobInfo {
+            etag,
+            modified: Utc::now(),
+        }
+    }
+}
+
+fn info_key(key: &[u8]) -> Vec<u8> {
+    std::iter::once(BLOB_INFO_TYPE)
+        .chain(key.iter().copied())
+        .collect()
+}
+
+/// Writes a blob along with its info.
+pub fn put(batch: &mut WriteBatch, key: &[u8], data: &[u8]) -> Result<BlobInfo, Error> {
+    let info = BlobInfo::new(data);
+    batch.put(key, data);
+    batch.put(info_key(key), serde_json::to_vec(&info)?);
+    Ok(info)
+}
+
+/// Deletes a blob along with its info.
+pub fn delete(batch: &mut WriteBatch, key: &[u8]) {
+    batch.delete(key);
+    batch.delete(info_key(key));
+}
+
+/// Writes a single blob along with its info.
+pub fn store(files: &FileDatabase, key: &[u8], data: &[u8]) -> Result<BlobInfo, Error> {
+    let mut batch = WriteBatch::default();
+    let info = put(&mut batch, key, data)?;
+    files.write(batch)?;
+    Ok(info)
+}
+
+/// Deletes a single blob along with its info.
+pub fn remove(files: &FileDatabase, key: &[u8]) -> Result<(), Error> {
+    let mut batch = WriteBatch::default();
+    delete(&mut batch, key);
+    files.write(batch)?;
+    Ok(())
+}
+
+/// A blob and its info, which is added for blobs written before infos were stored.
+pub fn load(files: &FileDatabase, key: &[u8]) -> Result<Option<(Vec<u8>, BlobInfo)>, Error> {
+    let Some(data) = files.get(key)? else {
+        return Ok(None);
+    };
+    if let Some(info) = files.get(info_key(key))? {
+        return Ok(Some((data, serde_json::from_slice(&info)?)));
+    }
+    let info = BlobInfo::new(&data);
+    files.put(info_key(key), serde_json::to_vec(&info)?)?;
+    Ok(Some((data, info)))
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Whether the client's copy is still current, `If-None-Match` takes precedence over
+/// `If-Modified-Since`.
+fn is_fresh(req: &HttpRequest, etag: &EntityTag, modified: SystemTime) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => req
+            .get_header::<IfModifiedSince>()
+            .map_or(false, |IfModifiedSince(since)| {
+                unix_seconds(modified) <= unix_seconds(since.into())
+            }),
+    }
+}
+
+/// The byte range asked for, unless the whole blob should be sent because there's no `Range`,
+/// its `If-Range` doesn't match, or several ranges are asked for. `Err` if it can't be satisfied.
+fn requested_range(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    modified: SystemTime,
+    length: u64,
+) -> Result<Option<(u64, u64)>, ()> {
+    let Some(Range::Bytes(ranges)) = req.get_header::<Range>() else {
+        return Ok(None);
+    };
+    let current = match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        // only an exact match, an older copy could have been modified within the same second
+        Some(IfRange::Date(date)) => unix_seconds(modified) == unix_seconds(date.into()),
+        None => true,
+    };
+    match ranges.as_slice() {
+        [range] if current => range.to_satisfiable_range(length).map(Some).ok_or(()),
+        _ => Ok(None),
+    }
+}
+
+/// Answers a request for a blob, with `304 Not Modified` if the client's copy is current and
+/// `206 Partial Content` if only part of it was asked for.
+pub fn respond(
+    req: &HttpRequest,
+    config: &Images,
+    content_type: &str,
+    data: Vec<u8>,
+    info: &BlobInfo,
+) -> HttpResponse {
+    let etag = EntityTag::new_strong(info.etag.clone());
+    let modified = SystemTime::from(info.modified);
+    let headers = |mut response: HttpResponseBuilder| {
+        response
+            .insert_header(ETag(etag.clone()))
+            .insert_header(LastModified(modified.into()))
+            .insert_header((header::CACHE_CONTROL, config.cache_control.as_str()))
+            .insert_header(AcceptRanges(vec![RangeUnit::Bytes]));
+        response
+    };
+
+    if is_fresh(req, &etag, modified) {
+        return headers(HttpResponse::NotModified()).finish();
+    }
+    let length = data.len() as u64;
+    match requested_range(req, &etag, modified, length) {
+        Ok(None) => headers(HttpResponse::Ok())
+            .content_type(content_type)
+            .body(data),
+        Ok(Some((first, last))) => headers(HttpResponse::PartialContent())
+            .content_type(content_type)
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((first, last)),
+                instance_length: Some(length),
+            }))
+            .body(data[first as usize..=last as usize].to_vec()),
+        Err(()) => headers(HttpResponse::RangeNotSatisfiable())
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(length),
+            }))
+            .finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{body::to_bytes, http::StatusCode, test::TestRequest};
+
+    use super::*;
+
+    const ETAG: &str = "0123abcd";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn http_date(time: SystemTime) -> String {
+        header::HttpDate::from(time).to_string()
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(TestRequest::default(), |req, (name, value)| {
+                req.insert_header((name.clone(), *value))
+            })
+            .to_http_request()
+    }
+
+    fn fresh(headers: &[(header::HeaderName, &str)]) -> bool {
+        is_fresh(
+            &request(headers),
+            &EntityTag::new_strong(ETAG.to_owned()),
+            modified(),
+        )
+    }
+
+    fn range(headers: &[(header::HeaderName, &str)]) -> Result<Option<(u64, u64)>, ()> {
+        requested_range(
+            &request(headers),
+            &EntityTag::new_strong(ETAG.to_owned()),
+            modified(),
+            10,
+        )
+    }
+
+    #[test]
+    fn unconditional_requests_are_not_fresh() {
+        assert!(!fresh(&[]));
+    }
+
+    #[test]
+    fn if_none_match_compares_etags_weakly() {
+        let matching = format!("\"{}\"", ETAG);
+        let weak = format!("W/\"{}\"", ETAG);
+        let several = format!("\"other\", \"{}\"", ETAG);
+        assert!(fresh(&[(header::IF_NONE_MATCH, &matching)]));
+        assert!(fresh(&[(header::IF_NONE_MATCH, &weak)]));
+        assert!(fresh(&[(header::IF_NONE_MATCH, &several)]));
+        assert!(fresh(&[(header::IF_NONE_MATCH, "*")]));
+        assert!(!fresh(&[(header::IF_NONE_MATCH, "\"other\"")]));
+    }
+
+    #[test]
+    fn if_modified_since_compares_seconds() {
+        let same = http_date(modified());
+        let later = http_date(modified() + Duration::from_secs(60));
+        let earlier = http_date(modified() - Duration::from_secs(1));
+        assert!(fresh(&[(header::IF_MODIFIED_SINCE, &same)]));
+        assert!(fresh(&[(header::IF_MODIFIED_SINCE, &later)]));
+        assert!(!fresh(&[(header::IF_MODIFIED_SINCE, &earlier)]));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let later = http_date(modified() + Duration::from_secs(60));
+        assert!(!fresh(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, &later),
+        ]));
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range(&[]), Ok(None));
+        assert_eq!(range(&[(header::RANGE, "bytes=2-5")]), Ok(Some((2, 5))));
+        // suffix
+        assert_eq!(range(&[(header::RANGE, "bytes=-3")]), Ok(Some((7, 9))));
+        assert_eq!(range(&[(header::RANGE, "bytes=-20")]), Ok(Some((0, 9))));
+        // open-ended
+        assert_eq!(range(&[(header::RANGE, "bytes=4-")]), Ok(Some((4, 9))));
+        assert_eq!(range(&[(header::RANGE, "bytes=8-20")]), Ok(Some((8, 9))));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(range(&[(header::RANGE, "bytes=10-")]), Err(()));
+        assert_eq!(range(&[(header::RANGE, "bytes=12-15")]), Err(()));
+        assert_eq!(range(&[(header::RANGE, "bytes=-0")]), Err(()));
+    }
+
+    #[test]
+    fn sends_everything_for_several_ranges() {
+        assert_eq!(range(&[(header::RANGE, "bytes=0-1,4-5")]), Ok(None));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_or_the_exact_date() {
+        let matching = format!("\"{}\"", ETAG);
+        let weak = format!("W/\"{}\"", ETAG);
+        let same = http_date(modified());
+        let later = http_date(modified() + Duration::from_secs(60));
+        let earlier = http_date(modified() - Duration::from_secs(60));
+        for (if_range, expected) in [
+            (matching.as_str(), Ok(Some((2, 5)))),
+            (weak.as_str(), Ok(None)),
+            ("\"other\"", Ok(None)),
+            (same.as_str(), Ok(Some((2, 5)))),
+            (later.as_str(), Ok(None)),
+            (earlier.as_str(), Ok(None)),
+        ] {
+            assert_eq!(
+                range(&[(header::RANGE, "bytes=2-5"), (header::IF_RANGE, if_range)]),
+                expected,
+                "If-Range: {}",
+                if_range
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn responds_with_the_matching_status() {
+        let data: Vec<u8> = (0..10).collect();
+        let info = BlobInfo {
+            etag: ETAG.to_owned(),
+            modified: modified().into(),
+        };
+        let config = Images::default();
+        let send = |headers: &[(header::HeaderName, &str)]| {
+            respond(&request(headers), &config, "image/png", data.clone(), &info)
+        };
+
+        let response = send(&[]);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::ETAG).unwrap(),
+            &format!("\"{}\"", ETAG)
+        );
+        assert_eq!(
+            response.headers().get(header::LAST_MODIFIED).unwrap(),
+            &http_date(modified())
+        );
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), data);
+
+        let matching = format!("\"{}\"", ETAG);
+        let response = send(&[(header::IF_NONE_MATCH, &matching)]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = send(&[(header::RANGE, "bytes=-3")]);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 7-9/10"
+        );
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), &data[7..]);
+
+        let response = send(&[(header::RANGE, "bytes=10-")]);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes */10"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Recognising uploaded images by their content and converting them to the configured format.
+
+use std::io::Cursor;
+
+use anyhow::Error;
+use chrono::NaiveDateTime;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Images, image_metadata};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Heic,
+    Avif,
+}
+
+/// Brands of ISO base media files that are HEIF images with HEVC
+const HEIC_BRANDS: [&[u8]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis"];
+const AVIF_BRANDS: [&[u8]; 2] = [b"avif", b"avis"];
+
+impl ImageFormat {
+    /// Recognises the format by its magic bytes, whatever the client claims it to be.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else if data.len() >= 12 && &data[4..8] == b"ftyp" {
+            // the major brand, followed by the minor version and the compatible brands
+            let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let header = &data[8..size.clamp(12, data.len())];
+            let brands =
+                std::iter::once(&header[..4]).chain(header.get(8..).unwrap_or(&[]).chunks_exact(4));
+            let mut format = None;
+            for brand in brands {
+                if AVIF_BRANDS.contains(&brand) {
+                    return Some(ImageFormat::Avif);
+                }
+                if HEIC_BRANDS.contains(&brand) {
+                    format = Some(ImageFormat::Heic);
+                }
+            }
+            format
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Heic => "image/heic",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+}
+
+#[cfg(feature = "heic")]
+fn decode_heic(data: &[u8]) -> Result<DynamicImage, Error> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(data)?;
+    let handle = context.primary_image_handle()?;
+    let decoded = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| anyhow::anyhow!("HEIC image without pixels"))?;
+    let (width, height) = (plane.width, plane.height);
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in plane.data.chunks(plane.stride).take(height as usize) {
+        pixels.extend_from_slice(&row[..width as usize * 3]);
+    }
+    let image = image::RgbImage::from_raw(width, height, pixels)
+        .ok_or_else(|| anyhow::anyhow!("Invalid HEIC image"))?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+/// Decodes an image, `None` if support for its format wasn't compiled in.
+pub fn decode(data: &[u8], format: ImageFormat) -> Result<Option<DynamicImage>, Error> {
+    let format = match format {
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        ImageFormat::Png => image::ImageFormat::Png,
+        ImageFormat::Webp => image::ImageFormat::WebP,
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => image::ImageFormat::Avif,
+        #[cfg(feature = "heic")]
+        ImageFormat::Heic => return Ok(Some(decode_heic(data)?)),
+        #[allow(unreachable_patterns)]
+        _ => return Ok(None),
+    };
+    Ok(Some(image::load_from_memory_with_format(data, format)?))
+}
+
+/// Encodes an image, which only works for JPEG, PNG and WebP.
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    config: &Images,
+) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            // JPEG has no transparency
+            JpegEncoder::new_with_quality(&mut data, config.jpeg_quality)
+                .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+        }
+        ImageFormat::Png => image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)?,
+        ImageFormat::Webp => {
+            image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::WebP)?
+        }
+        ImageFormat::Heic | ImageFormat::Avif => {
+            anyhow::bail!("Images can't be converted to {:?}", format)
+        }
+    }
+    Ok(data)
+}
+
+/// An upload as it's stored
+pub struct Upload {
+    pub data: Vec<u8>,
+    pub format: ImageFormat,
+    /// From the EXIF metadata, in the camera's time
+    pub taken_at: Option<NaiveDateTime>,
+}
+
+/// Checks an upload, turns it upright and converts it to the canonical format, if one is
+/// configured. Its metadata is dropped unless configured otherwise. All that only works if the
+/// upload can be decoded, so anything else is only accepted if its metadata may be kept.
+/// Returns `None` for anything that isn't a supported image.
+pub fn normalize(data: Vec<u8>, config: &Images) -> Result<Option<Upload>, Error> {
+    let Some(format) = ImageFormat::sniff(&data) else {
+        return Ok(None);
+    };
+    let exif = image_metadata::read(&data);
+    let taken_at = exif.as_ref().and_then(image_metadata::taken_at);
+    let orientation = match format {
+        // HEIF has its own transformations, which the decoder applies
+        ImageFormat::Heic | ImageFormat::Avif => 1,
+        _ => exif.as_ref().map_or(1, image_metadata::orientation),
+    };
+    let target = config.canonical_format.unwrap_or(format);
+    if target == format && orientation == 1 {
+        if !config.strip_metadata {
+            return Ok(Some(Upload {
+                data,
+                format,
+                taken_at,
+            }));
+        }
+        // spares re-encoding, which loses quality
+        if let Some(data) = image_metadata::strip(&data, format) {
+            return Ok(Some(Upload {
+                data,
+                format,
+                taken_at,
+            }));
+        }
+    }
+
+    let Some(image) = decode(&data, format)? else {
+        if config.strip_metadata {
+            log::warn!(
+                "Refusing {:?} upload, its metadata can't be stripped without decoding it.",
+                format
+            );
+            return Ok(None);
+        }
+        log::warn!(
+            "Keeping {:?} upload as is, decoding isn't supported.",
+            format
+        );
+        return Ok(Some(Upload {
+            data,
+            format,
+            taken_at,
+        }));
+    };
+    // encoding only works for some formats, including the canonical ones
+    let target = match target {
+        ImageFormat::Heic | ImageFormat::Avif => ImageFormat::Jpeg,
+        target => target,
+    };
+    let image = image_metadata::orient(image, orientation);
+    Ok(Some(Upload {
+        data: encode(&image, target, config)?,
+        format: target,
+        taken_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ISO base media file header with the given major and compatible brands.
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut data = size.to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(major);
+        data.extend_from_slice(&[0; 4]);
+        for brand in compatible {
+            data.extend_from_slice(*brand);
+        }
+        data.extend_from_slice(b"\0\0\0\x08meta");
+        data
+    }
+
+    #[test]
+    fn sniffs_accepted_formats() {
+        assert_eq!(
+            ImageFormat::sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::sniff(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::sniff(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(
+            ImageFormat::sniff(&ftyp(b"heic", &[b"mif1", b"heic"])),
+            Some(ImageFormat::Heic)
+        );
+        assert_eq!(
+            ImageFormat::sniff(&ftyp(b"avif", &[b"mif1", b"miaf"])),
+            Some(ImageFormat::Avif)
+        );
+        // AVIF only listed among the compatible brands
+        assert_eq!(
+            ImageFormat::sniff(&ftyp(b"mif1", &[b"heic", b"avif"])),
+            Some(ImageFormat::Avif)
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(ImageFormat::sniff(b"GIF89a\x01\0\x01\0"), None);
+        assert_eq!(ImageFormat::sniff(b"%PDF-1.7"), None);
+        assert_eq!(ImageFormat::sniff(&ftyp(b"isom", &[b"mp41"])), None);
+        assert_eq!(ImageFormat::sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(ImageFormat::sniff(b""), None);
+        // the header claims to be longer than the file
+        let mut truncated = ftyp(b"heic", &[]);
+        truncated[3] = 0xff;
+        truncated.truncate(12);
+        assert_eq!(ImageFormat::sniff(&truncated), Some(ImageFormat::Heic));
+    }
+}
@@ -0,0 +1,312 @@
+//! Metadata of uploaded photos: how they have to be turned, when they were taken, and removing
+//! it altogether.
+
+use std::io::Cursor;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Reader, Tag, Value};
+use image::DynamicImage;
+
+use crate::image_formats::ImageFormat;
+
+/// The EXIF metadata of an image, if it has any.
+pub fn read(data: &[u8]) -> Option<Exif> {
+    Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+}
+
+/// How the image has to be transformed to be upright, 1 if it already is.
+pub fn orientation(exif: &Exif) -> u32 {
+    exif.get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// When the photo was taken, in the camera's time as EXIF rarely has the time zone.
+pub fn taken_at(exif: &Exif) -> Option<NaiveDateTime> {
+    [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .into_iter()
+        .find_map(|tag| {
+            let field = exif.get_field(tag, In::PRIMARY)?;
+            let Value::Ascii(ref values) = field.value else {
+                return None;
+            };
+            let time = exif::DateTime::from_ascii(values.first()?).ok()?;
+            NaiveDate::from_ymd_opt(time.year.into(), time.month.into(), time.day.into())?
+                .and_hms_opt(time.hour.into(), time.minute.into(), time.second.into())
+        })
+}
+
+/// Turns the pixels upright as given by the EXIF orientation.
+pub fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        // mirrored along the diagonals
+        5 => image.rotate90().fliph(),
+        7 => image.rotate270().fliph(),
+        6 => image.rotate90(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Removes EXIF, XMP and other metadata from a JPEG, PNG or WebP file without re-encoding it.
+/// `None` for other formats and files that don't have the expected structure.
+pub fn strip(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::Png => strip_png(data),
+        ImageFormat::Webp => strip_webp(data),
+        ImageFormat::Heic | ImageFormat::Avif => None,
+    }
+}
+
+/// Keeps JFIF, ICC profiles and Adobe colour transforms, drops all other application segments
+/// and comments.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = data.get(..2)?.to_vec();
+    let mut position = 2;
+    loop {
+        if *data.get(position)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(position + 1)?;
+        match marker {
+            // padding before a marker
+            0xff => position += 1,
+            // no length
+            0x01 | 0xd0..=0xd7 => {
+                stripped.extend_from_slice(&data[position..position + 2]);
+                position += 2;
+            }
+            // start of scan, the entropy coded data follows up to the end of the image
+            0xda => {
+                stripped.extend_from_slice(&data[position..]);
+                return Some(stripped);
+            }
+            _ => {
+                let length =
+                    u16::from_be_bytes([*data.get(position + 2)?, *data.get(position + 3)?]);
+                if length < 2 {
+                    return None;
+                }
+                let end = position + 2 + usize::from(length);
+                let segment = data.get(position..end)?;
+                let payload = &segment[4..];
+                let keep = match marker {
+                    0xe0 => true,
+                    0xe2 => payload.starts_with(b"ICC_PROFILE\0"),
+                    0xee => payload.starts_with(b"Adobe"),
+                    0xe1..=0xef | 0xfe => false,
+                    _ => true,
+                };
+                if keep {
+                    stripped.extend_from_slice(segment);
+                }
+                position = end;
+            }
+        }
+    }
+}
+
+/// Drops the EXIF, text and timestamp chunks.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = data.get(..8)?.to_vec();
+    let mut position = 8;
+    while position < data.len() {
+        let length = u32::from_be_bytes(data.get(position..position + 4)?.try_into().ok()?);
+        // length, type, data and CRC
+        let end = position + 12 + usize::try_from(length).ok()?;
+        let chunk = data.get(position..end)?;
+        if !matches!(
+            &chunk[4..8],
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+        ) {
+            stripped.extend_from_slice(chunk);
+        }
+        position = end;
+    }
+    Some(stripped)
+}
+
+/// Flags in the extended header telling that there are EXIF and XMP chunks
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+
+/// Drops the EXIF and XMP chunks and their flags in the extended header.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    // the RIFF header, its size is filled in at the end
+    let mut stripped = data.get(..12)?.to_vec();
+    let mut position = 12;
+    while position < data.len() {
+        let size = u32::from_le_bytes(data.get(position + 4..position + 8)?.try_into().ok()?);
+        // chunks are padded to an even size
+        let end = position + 8 + usize::try_from(size).ok()? + (size as usize & 1);
+        let chunk = data.get(position..end.min(data.len()))?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = stripped.len();
+                stripped.extend_from_slice(chunk);
+                *stripped.get_mut(start + 8)? &= !(VP8X_EXIF | VP8X_XMP);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        position = end;
+    }
+    let size = u32::try_from(stripped.len() - 8).ok()?;
+    stripped[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    /// A pixel that tells where it came from
+    fn coordinates(x: u32, y: u32) -> Rgb<u8> {
+        Rgb([x as u8, y as u8, 0])
+    }
+
+    #[test]
+    fn orient_turns_images_upright() {
+        let (width, height) = (3, 2);
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, coordinates));
+        // where each pixel ends up, for every orientation
+        let expected: [(u32, fn(u32, u32) -> (u32, u32)); 8] = [
+            (1, |x, y| (x, y)),
+            (2, |x, y| (3 - 1 - x, y)),
+            (3, |x, y| (3 - 1 - x, 2 - 1 - y)),
+            (4, |x, y| (x, 2 - 1 - y)),
+            (5, |x, y| (y, x)),
+            (6, |x, y| (2 - 1 - y, x)),
+            (7, |x, y| (2 - 1 - y, 3 - 1 - x)),
+            (8, |x, y| (y, 3 - 1 - x)),
+        ];
+        for (orientation, target) in expected {
+            let oriented = orient(image.clone(), orientation).to_rgb8();
+            let (target_width, target_height) = if orientation >= 5 {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            assert_eq!(
+                oriented.dimensions(),
+                (target_width, target_height),
+                "orientation {}",
+                orientation
+            );
+            for (x, y) in (0..width).flat_map(|x| (0..height).map(move |y| (x, y))) {
+                let (target_x, target_y) = target(x, y);
+                assert_eq!(
+                    *oriented.get_pixel(target_x, target_y),
+                    coordinates(x, y),
+                    "orientation {}",
+                    orientation
+                );
+            }
+        }
+    }
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_fn(4, 4, coordinates))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn strip_jpeg_drops_exif_xmp_and_comments() {
+        let clean = encode(ImageOutputFormat::Jpeg(90));
+        let mut tagged = clean[..2].to_vec();
+        for (marker, payload) in [
+            (0xe1, &b"Exif\0\0GPS"[..]),
+            (0xe1, &b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"[..]),
+            (0xfe, &b"a comment"[..]),
+        ] {
+            tagged.extend_from_slice(&[0xff, marker]);
+            tagged.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            tagged.extend_from_slice(payload);
+        }
+        tagged.extend_from_slice(&clean[2..]);
+
+        let stripped = strip(&tagged, ImageFormat::Jpeg).unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert_eq!(stripped, clean);
+    }
+
+    #[test]
+    fn strip_png_drops_exif_and_text() {
+        let clean = encode(ImageOutputFormat::Png);
+        // after the signature and the header chunk
+        let header_end = 8 + 12 + 13;
+        let mut tagged = clean[..header_end].to_vec();
+        for (kind, payload) in [
+            (b"eXIf", &b"MM\0*GPS"[..]),
+            (b"iTXt", &b"XML:com.adobe.xmp"[..]),
+        ] {
+            tagged.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            tagged.extend_from_slice(kind);
+            tagged.extend_from_slice(payload);
+            tagged.extend_from_slice(&[0; 4]);
+        }
+        tagged.extend_from_slice(&clean[header_end..]);
+
+        let stripped = strip(&tagged, ImageFormat::Png).unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert_eq!(stripped, clean);
+    }
+
+    #[test]
+    fn strip_webp_drops_exif_and_xmp_chunks_and_flags() {
+        let chunk = |kind: &[u8], payload: &[u8]| {
+            let mut chunk = kind.to_vec();
+            chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(payload);
+            if payload.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+        let riff = |chunks: &[Vec<u8>]| {
+            let body: Vec<u8> = chunks.concat();
+            let mut data = b"RIFF".to_vec();
+            data.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+            data.extend_from_slice(b"WEBP");
+            data.extend_from_slice(&body);
+            data
+        };
+        let header = |flags: u8| chunk(b"VP8X", &[flags, 0, 0, 0, 3, 0, 0, 3, 0, 0]);
+        let pixels = chunk(b"VP8L", b"pixels");
+        let tagged = riff(&[
+            header(VP8X_EXIF | VP8X_XMP),
+            pixels.clone(),
+            chunk(b"EXIF", b"GPS"),
+            chunk(b"XMP ", b"<x:xmpmeta/>"),
+        ]);
+
+        let stripped = strip(&tagged, ImageFormat::Webp).unwrap();
+        assert_eq!(stripped, riff(&[header(0), pixels]));
+    }
+
+    #[test]
+    fn strip_rejects_truncated_files() {
+        let clean = encode(ImageOutputFormat::Png);
+        assert_eq!(strip(&clean[..clean.len() - 3], ImageFormat::Png), None);
+        assert_eq!(strip(b"\xff\xd8\xff\xe1\x00", ImageFormat::Jpeg), None);
+    }
+}
@@ -4,157 +4,638 @@ use actix_session::Session;
 use actix_web::{
     delete,
     error::{ErrorBadRequest, ErrorInternalServerError},
-    get,
-    http::header::HeaderValue,
-    post, web, HttpRequest, HttpResponse,
+    get, post, put, web, HttpRequest, HttpResponse,
 };
+use anyhow::Error;
 use async_graphql::futures_util::StreamExt;
 use datamatrix::{DataMatrix, SymbolList};
+use serde::Deserialize;
 use uuid::Uuid;
 
 use crate::{
-    schema::{CONTAINER_IMAGE_TYPE, ITEM_IMAGE_TYPE},
-    user_session, FileDatabase,
+    changes::{Broker, ChangeKind},
+    config::Config,
+    gallery::{self, image_key, Owner},
+    history::Tracker,
+    http_cache,
+    image_formats::{self, ImageFormat, Upload},
+    schema::{location_image_key, MutationRoot},
+    thumbnails::{self, Lookup, Variant},
+    user_session::{self, Credential},
+    users::{Role, User},
+    FileDatabase, MetadataDatabase,
 };
 
-#[post("/image/container/{id}")]
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    caption: Option<String>,
+}
+
+/// New caption of an image, `primary` makes it the primary image of its gallery
+#[derive(Deserialize)]
+pub struct ImageUpdate {
+    caption: Option<String>,
+    #[serde(default)]
+    primary: bool,
+}
+
+async fn notify(broker: &Broker, owner: Owner) {
+    match owner {
+        Owner::Item(id) => broker.items(ChangeKind::Updated, &[id]).await,
+        Owner::Container(id) => broker.containers(ChangeKind::Updated, &[id]).await,
+    }
+}
+
+async fn list_images(
+    metadata: &MetadataDatabase,
+    owner: Owner,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut db = metadata.acquire().await.map_err(ErrorInternalServerError)?;
+    let images = gallery::list(&mut db, owner)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(images))
+}
+
+/// Reads an upload and checks by its content that it's a supported image, whatever its
+/// `content-type` says. It is turned upright, stripped of its metadata and converted to the
+/// canonical format as configured.
+async fn read_image(
+    config: &Arc<Config>,
+    mut data: web::Payload,
+) -> Result<Option<Upload>, actix_web::Error> {
+    let mut bytes = web::BytesMut::new();
+    while let Some(item) = data.next().await {
+        bytes.extend_from_slice(&item?);
+    }
+    let config = config.clone();
+    // converting takes a while
+    web::block(move || image_formats::normalize(bytes.to_vec(), &config.images))
+        .await?
+        .map_err(ErrorBadRequest)
+}
+
+/// Renders the thumbnails of an image that was just stored under `key`. They're rendered on
+/// demand if that fails, so it doesn't fail the upload.
+async fn render_thumbnails(
+    db: &Arc<FileDatabase>,
+    config: &Arc<Config>,
+    key: Vec<u8>,
+    data: Vec<u8>,
+) {
+    let (db, config) = (db.clone(), config.clone());
+    let result = web::block(move || {
+        thumbnails::delete_variants(&db, &key)?;
+        thumbnails::generate(&db, &key, &data, &config.images)
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::error!("Failed rendering thumbnails: {}", err),
+        Err(err) => log::error!("Failed rendering thumbnails: {}", err),
+    }
+}
+
+/// Serves a resized variant of the image stored under `source`.
+async fn fetch_variant(
+    req: &HttpRequest,
+    db: &Arc<FileDatabase>,
+    config: &Arc<Config>,
+    source: Vec<u8>,
+    variant: Variant,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (files, shared) = (db.clone(), config.clone());
+    let lookup = web::block(move || thumbnails::fetch(&files, &source, &variant, &shared.images))
+        .await?
+        .map_err(ErrorInternalServerError)?;
+    match lookup {
+        Lookup::Found(data, info) => {
+            let content_type =
+                ImageFormat::sniff(&data).map_or("image/jpeg", ImageFormat::content_type);
+            Ok(http_cache::respond(
+                req,
+                &config.images,
+                content_type,
+                data,
+                &info,
+            ))
+        }
+        Lookup::Missing => Ok(HttpResponse::NotFound().body("No such image")),
+        Lookup::Unsupported => Ok(HttpResponse::UnprocessableEntity()
+            .body("The image can't be resized, its format isn't supported.")),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_image(
+    db: &Arc<FileDatabase>,
+    metadata: &MetadataDatabase,
+    config: &Arc<Config>,
+    broker: &Broker,
+    user: &User,
+    credential: Credential,
+    owner: Owner,
+    caption: Option<String>,
+    data: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(upload) = read_image(config, data).await? else {
+        return Ok(HttpResponse::UnsupportedMediaType().body("Unsupported image format."));
+    };
+
+    match gallery::add(db, metadata, owner, user.id, credential, caption, &upload)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(image) => {
+            render_thumbnails(db, config, image_key(image.id), upload.data).await;
+            notify(broker, owner).await;
+            Ok(HttpResponse::Ok().json(image))
+        }
+        None => Ok(HttpResponse::NotFound().body("No such object")),
+    }
+}
+
+async fn reorder_images(
+    metadata: &MetadataDatabase,
+    broker: &Broker,
+    user: &User,
+    credential: Credential,
+    owner: Owner,
+    order: &[Uuid],
+) -> Result<HttpResponse, actix_web::Error> {
+    if gallery::reorder(metadata, user.id, credential, owner, order)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        notify(broker, owner).await;
+        Ok(HttpResponse::Ok().body("OK"))
+    } else {
+        Ok(HttpResponse::NotFound().body("No such object"))
+    }
+}
+
+#[get("/images/container/{id}")]
+pub async fn list_container_images(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    list_images(&metadata, Owner::Container(uuid)).await
+}
+
+/// Adds an image to the container's gallery
+#[post("/images/container/{id}")]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_container_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
     id: web::Path<(String,)>,
+    query: web::Query<UploadQuery>,
     req: HttpRequest,
-    mut data: web::Payload,
+    data: web::Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
-    }
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
     let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(CONTAINER_IMAGE_TYPE)
-        .chain(uuid.as_bytes().iter().copied())
-        .collect();
-    if req.headers().get("content-type") != Some(&HeaderValue::from_static("image/jpeg")) {
-        return Ok(HttpResponse::BadRequest().body("Invalid content type."));
-    }
+    upload_image(
+        &db,
+        &metadata,
+        &config,
+        &broker,
+        &user,
+        credential,
+        Owner::Container(uuid),
+        query.into_inner().caption,
+        data,
+    )
+    .await
+}
 
-    let mut bytes = web::BytesMut::new();
-    while let Some(item) = data.next().await {
-        bytes.extend_from_slice(&item?);
-    }
+/// Puts the listed images first, in that order
+#[put("/images/container/{id}/order")]
+#[allow(clippy::too_many_arguments)]
+pub async fn reorder_container_images(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    order: web::Json<Vec<Uuid>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    reorder_images(
+        &metadata,
+        &broker,
+        &user,
+        credential,
+        Owner::Container(uuid),
+        &order,
+    )
+    .await
+}
 
-    db.put(key, &bytes).map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().body("OK"))
+#[get("/images/item/{id}")]
+pub async fn list_item_images(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    list_images(&metadata, Owner::Item(uuid)).await
 }
 
-#[get("/image/container/{id}")]
-pub async fn fetch_container_image(
+/// Adds an image to the item's gallery
+#[post("/images/item/{id}")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_item_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
     id: web::Path<(String,)>,
+    query: web::Query<UploadQuery>,
+    req: HttpRequest,
+    data: web::Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
-    }
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
     let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(CONTAINER_IMAGE_TYPE)
-        .chain(uuid.as_bytes().iter().copied())
-        .collect();
+    upload_image(
+        &db,
+        &metadata,
+        &config,
+        &broker,
+        &user,
+        credential,
+        Owner::Item(uuid),
+        query.into_inner().caption,
+        data,
+    )
+    .await
+}
 
-    if let Some(data) = db.get(key).map_err(ErrorInternalServerError)? {
-        Ok(HttpResponse::Ok().content_type("image/jpeg").body(data))
+/// Puts the listed images first, in that order
+#[put("/images/item/{id}/order")]
+#[allow(clippy::too_many_arguments)]
+pub async fn reorder_item_images(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    order: web::Json<Vec<Uuid>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    reorder_images(
+        &metadata,
+        &broker,
+        &user,
+        credential,
+        Owner::Item(uuid),
+        &order,
+    )
+    .await
+}
+
+/// Serves a gallery image as uploaded, or a resized variant of it.
+async fn serve_image(
+    req: &HttpRequest,
+    db: &Arc<FileDatabase>,
+    config: &Arc<Config>,
+    uuid: Uuid,
+    content_type: &str,
+    variant: Variant,
+) -> Result<HttpResponse, actix_web::Error> {
+    if variant.is_resized() {
+        return fetch_variant(req, db, config, image_key(uuid), variant).await;
+    }
+    if let Some((data, info)) =
+        http_cache::load(db, &image_key(uuid)).map_err(ErrorInternalServerError)?
+    {
+        Ok(http_cache::respond(
+            req,
+            &config.images,
+            content_type,
+            data,
+            &info,
+        ))
     } else {
         Ok(HttpResponse::NotFound().body("No such image"))
     }
 }
 
-#[delete("/image/container/{id}")]
-pub async fn delete_container_image(
+/// Serves the primary image of a gallery, where the single image of items and containers was
+/// before galleries.
+async fn serve_primary_image(
+    req: &HttpRequest,
+    db: &Arc<FileDatabase>,
+    metadata: &MetadataDatabase,
+    config: &Arc<Config>,
+    owner: Owner,
+    variant: Variant,
+) -> Result<HttpResponse, actix_web::Error> {
+    match gallery::primary(metadata, owner)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(image) => serve_image(req, db, config, image.id, &image.content_type, variant).await,
+        None => Ok(HttpResponse::NotFound().body("No such image")),
+    }
+}
+
+/// The image as uploaded, or resized with `?w=&h=&fit=`
+#[get("/image/{id}")]
+pub async fn fetch_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
     id: web::Path<(String,)>,
+    variant: web::Query<Variant>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
-    }
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
     let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(CONTAINER_IMAGE_TYPE)
-        .chain(uuid.as_bytes().iter().copied())
-        .collect();
-    db.delete(key).map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().body("OK"))
+
+    let Some(content_type) = gallery::content_type(&metadata, uuid)
+        .await
+        .map_err(ErrorInternalServerError)?
+    else {
+        return Ok(HttpResponse::NotFound().body("No such image"));
+    };
+    serve_image(
+        &req,
+        &db,
+        &config,
+        uuid,
+        &content_type,
+        variant.into_inner(),
+    )
+    .await
 }
 
-#[post("/image/container/{container_id}/item/{item_id}")]
-pub async fn upload_item_image(
+/// The primary image of the container's gallery, like `/image/{id}`
+#[get("/image/container/{id}")]
+pub async fn fetch_container_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    id: web::Path<(String,)>,
+    variant: web::Query<Variant>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    serve_primary_image(
+        &req,
+        &db,
+        &metadata,
+        &config,
+        Owner::Container(uuid),
+        variant.into_inner(),
+    )
+    .await
+}
+
+/// The primary image of the item's gallery, like `/image/{id}`. The container is only part of
+/// the path for old clients, the item may have moved since.
+#[get("/image/container/{container_id}/item/{item_id}")]
+pub async fn fetch_item_image(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
     id: web::Path<(String, String)>,
+    variant: web::Query<Variant>,
     req: HttpRequest,
-    mut data: web::Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    let (_, item_id) = id.into_inner();
+    let uuid = item_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    serve_primary_image(
+        &req,
+        &db,
+        &metadata,
+        &config,
+        Owner::Item(uuid),
+        variant.into_inner(),
+    )
+    .await
+}
+
+#[put("/image/{id}")]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_image(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    update: web::Json<ImageUpdate>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    let ImageUpdate { caption, primary } = update.into_inner();
+    match gallery::update(&metadata, user.id, credential, uuid, caption, primary)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(owner) => {
+            notify(&broker, owner).await;
+            Ok(HttpResponse::Ok().body("OK"))
+        }
+        None => Ok(HttpResponse::NotFound().body("No such image")),
     }
-    let (container_id, item_id) = id.into_inner();
-    let container_uuid = container_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let item_uuid = item_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(ITEM_IMAGE_TYPE)
-        .chain(container_uuid.as_bytes().iter().copied())
-        .chain(item_uuid.as_bytes().iter().copied())
-        .collect();
-    if req.headers().get("content-type") != Some(&HeaderValue::from_static("image/jpeg")) {
-        return Ok(HttpResponse::BadRequest().body("Invalid content type."));
+}
+
+#[delete("/image/{id}")]
+pub async fn delete_image(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    match gallery::delete(&db, &metadata, user.id, credential, uuid)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(owner) => {
+            notify(&broker, owner).await;
+            Ok(HttpResponse::Ok().body("OK"))
+        }
+        None => Ok(HttpResponse::NotFound().body("No such image")),
     }
+}
 
-    let mut bytes = web::BytesMut::new();
-    while let Some(item) = data.next().await {
-        bytes.extend_from_slice(&item?);
+/// Replaces or removes the floor plan of a location as one tracked change. Returns whether the
+/// location exists and isn't in the trash.
+async fn change_floor_plan(
+    db: &Arc<FileDatabase>,
+    metadata: &MetadataDatabase,
+    user: &User,
+    credential: Credential,
+    id: Uuid,
+    floor_plan: Option<&[u8]>,
+) -> Result<bool, Error> {
+    let mut tx = metadata.begin().await?;
+    let uuid = id.as_bytes().to_vec();
+    if MutationRoot::live_location(&mut tx, Some(uuid.clone())).await? != Some(uuid) {
+        return Ok(false);
     }
+    let tracker = Tracker::with_floor_plans(&mut tx, db.clone(), &[id]).await?;
+    let key = location_image_key(id);
+    match floor_plan {
+        Some(data) => {
+            http_cache::store(db, &key, data)?;
+        }
+        None => {
+            http_cache::remove(db, &key)?;
+            thumbnails::delete_variants(db, &key)?;
+        }
+    }
+    tracker
+        .record_by(
+            &mut tx,
+            Some(user.id),
+            Some(credential),
+            ChangeKind::Updated,
+        )
+        .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[post("/image/location/{id}")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_location_image(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+    data: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    let Some(Upload { data: bytes, .. }) = read_image(&config, data).await? else {
+        return Ok(HttpResponse::UnsupportedMediaType().body("Unsupported image format."));
+    };
 
-    db.put(key, &bytes).map_err(ErrorInternalServerError)?;
+    if !change_floor_plan(&db, &metadata, &user, credential, uuid, Some(&bytes))
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::NotFound().body("No such location"));
+    }
+    render_thumbnails(&db, &config, location_image_key(uuid), bytes).await;
+    broker.locations(ChangeKind::Updated, &[uuid]).await;
     Ok(HttpResponse::Ok().body("OK"))
 }
 
-#[get("/image/container/{container_id}/item/{item_id}")]
-pub async fn fetch_item_image(
+/// The floor plan as uploaded, or resized with `?w=&h=&fit=`
+#[get("/image/location/{id}")]
+pub async fn fetch_location_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
-    id: web::Path<(String, String)>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    id: web::Path<(String,)>,
+    variant: web::Query<Variant>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    if variant.is_resized() {
+        return fetch_variant(
+            &req,
+            &db,
+            &config,
+            location_image_key(uuid),
+            variant.into_inner(),
+        )
+        .await;
     }
-    let (container_id, item_id) = id.into_inner();
-    let container_uuid = container_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let item_uuid = item_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(ITEM_IMAGE_TYPE)
-        .chain(container_uuid.as_bytes().iter().copied())
-        .chain(item_uuid.as_bytes().iter().copied())
-        .collect();
 
-    if let Some(data) = db.get(key).map_err(ErrorInternalServerError)? {
-        Ok(HttpResponse::Ok().content_type("image/jpeg").body(data))
+    if let Some((data, info)) =
+        http_cache::load(&db, &location_image_key(uuid)).map_err(ErrorInternalServerError)?
+    {
+        // floor plans have no metadata, but they were checked on upload
+        let content_type =
+            ImageFormat::sniff(&data).map_or("image/jpeg", ImageFormat::content_type);
+        Ok(http_cache::respond(
+            &req,
+            &config.images,
+            content_type,
+            data,
+            &info,
+        ))
     } else {
         Ok(HttpResponse::NotFound().body("No such image"))
     }
 }
 
-#[delete("/image/container/{container_id}/item/{item_id}")]
-pub async fn delete_item_image(
+#[delete("/image/location/{id}")]
+pub async fn delete_location_image(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
-    id: web::Path<(String, String)>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    broker: web::Data<Arc<Broker>>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(response) = user_session::verify(&session, &db) {
-        return Ok(response);
-    }
-    let (container_id, item_id) = id.into_inner();
-    let container_uuid = container_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let item_uuid = item_id.parse::<Uuid>().map_err(ErrorBadRequest)?;
-    let key: Vec<u8> = std::iter::once(ITEM_IMAGE_TYPE)
-        .chain(container_uuid.as_bytes().iter().copied())
-        .chain(item_uuid.as_bytes().iter().copied())
-        .collect();
-    db.delete(key).map_err(ErrorInternalServerError)?;
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    user_session::require_role(&user, Role::Editor)?;
+    let uuid = id.into_inner().0.parse::<Uuid>().map_err(ErrorBadRequest)?;
+    if !change_floor_plan(&db, &metadata, &user, credential, uuid, None)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Ok(HttpResponse::NotFound().body("No such location"));
+    }
+    broker.locations(ChangeKind::Updated, &[uuid]).await;
     Ok(HttpResponse::Ok().body("OK"))
 }
 
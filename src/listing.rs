@@ -0,0 +1,727 @@
+//! Filtering, sorting and pagination of the list queries, all done in SQL.
+
+use anyhow::Error;
+use async_graphql::{
+    connection::{Connection, CursorType, Edge},
+    Enum, InputObject, OutputType, SimpleObject,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, QueryBuilder, Row, Sqlite};
+use uuid::Uuid;
+
+/// Subquery for a location and all of its sublocations.
+const LOCATION_SUBTREE: &str = "(WITH RECURSIVE subtree(uuid) AS (SELECT ";
+const LOCATION_SUBTREE_END: &str =
+    " UNION SELECT l.uuid FROM locations l JOIN subtree s ON l.parent = s.uuid) SELECT uuid FROM subtree)";
+
+/// Additional fields of all connections
+#[derive(Debug, Clone, SimpleObject)]
+pub struct TotalCount {
+    /// Number of matching entries across all pages
+    pub total_count: usize,
+}
+
+pub type CountedConnection<T> = Connection<Cursor, T, TotalCount>;
+
+/// Pages are never longer than this, also without `first` or `last`.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Value of the sort key of an entry, as stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Integer(i64),
+    Text(String),
+}
+
+/// Position of an entry in a sorted list: its sort key and, where that isn't unique, its primary
+/// key. Pages continue right next to it even if entries were added or removed in between.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub key: SortKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+}
+
+impl CursorType for Cursor {
+    type Error = serde_json::Error;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        serde_json::from_str(s)
+    }
+
+    fn encode_cursor(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Sort order of a list query.
+#[derive(Debug, Clone, Copy)]
+pub struct Order {
+    /// SQL expression, which must never be NULL
+    key: &'static str,
+    /// Column breaking ties between equal keys, none if the key is unique
+    id: Option<&'static str>,
+    descending: bool,
+}
+
+impl Order {
+    /// Sorts by `key`, and by the `uuid` column where keys are equal.
+    pub fn new(key: &'static str, descending: bool) -> Self {
+        Self {
+            key,
+            id: Some("uuid"),
+            descending,
+        }
+    }
+
+    /// Sorts by a key that is unique itself.
+    pub fn unique(key: &'static str, descending: bool) -> Self {
+        Self {
+            key,
+            id: None,
+            descending,
+        }
+    }
+}
+
+/// Page of a list selected by Relay cursor arguments. The query selects the page's entries and
+/// one more, which tells whether there are further pages.
+#[derive(Debug)]
+pub struct Page {
+    order: Order,
+    after: Option<Cursor>,
+    before: Option<Cursor>,
+    first: Option<usize>,
+    last: Option<usize>,
+}
+
+impl Page {
+    pub fn new(
+        order: Order,
+        after: Option<Cursor>,
+        before: Option<Cursor>,
+        first: Option<usize>,
+        last: Option<usize>,
+    ) -> Self {
+        Self {
+            order,
+            after,
+            before,
+            first,
+            last,
+        }
+    }
+
+    /// Only `last` counts from the end of the list, `first` wins if both are given.
+    fn backwards(&self) -> bool {
+        self.first.is_none() && self.last.is_some()
+    }
+
+    fn limit(&self) -> usize {
+        self.first
+            .or(self.last)
+            .unwrap_or(MAX_PAGE_SIZE)
+            .min(MAX_PAGE_SIZE)
+    }
+
+    /// Appends the sort key (and the tie breaker) to the selected columns.
+    pub fn push_columns<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) {
+        query.push(format!(", {} AS sort_key", self.order.key));
+        if let Some(id) = self.order.id {
+            query.push(format!(", {} AS sort_id", id));
+        }
+    }
+
+    fn push_bound<'a>(
+        &self,
+        query: &mut QueryBuilder<'a, Sqlite>,
+        cursor: &Cursor,
+        greater: bool,
+    ) -> Result<(), Error> {
+        query.push(" AND (").push(self.order.key);
+        if let Some(id) = self.order.id {
+            query.push(", ").push(id);
+        }
+        query.push(if greater { ") > (" } else { ") < (" });
+        match &cursor.key {
+            SortKey::Integer(key) => query.push_bind(*key),
+            SortKey::Text(key) => query.push_bind(key.clone()),
+        };
+        if self.order.id.is_some() {
+            let id = cursor.id.ok_or_else(|| anyhow::anyhow!("Invalid cursor"))?;
+            query.push(", ").push_bind(id);
+        }
+        query.push(")");
+        Ok(())
+    }
+
+    /// Appends the conditions for the cursors, the order and the limit to a query that already
+    /// has a `WHERE` clause.
+    pub fn push_range<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) -> Result<(), Error> {
+        if let Some(after) = &self.after {
+            self.push_bound(query, after, !self.order.descending)?;
+        }
+        if let Some(before) = &self.before {
+            self.push_bound(query, before, self.order.descending)?;
+        }
+        let direction = if self.order.descending != self.backwards() {
+            "DESC"
+        } else {
+            "ASC"
+        };
+        query.push(format!(" ORDER BY {} {}", self.order.key, direction));
+        if let Some(id) = self.order.id {
+            query.push(format!(", {} {}", id, direction));
+        }
+        query.push(" LIMIT ").push_bind(self.limit() as i64 + 1);
+        Ok(())
+    }
+
+    /// Builds the connection from the rows the query returned.
+    pub fn connection<T: OutputType>(
+        &self,
+        total: usize,
+        rows: Vec<SqliteRow>,
+        mut node: impl FnMut(&SqliteRow) -> Result<T, sqlx::Error>,
+    ) -> Result<CountedConnection<T>, Error> {
+        let entries = rows
+            .iter()
+            .map(|row| {
+                let key = row
+                    .try_get("sort_key")
+                    .map(SortKey::Integer)
+                    .or_else(|_| row.try_get("sort_key").map(SortKey::Text))?;
+                let id = match self.order.id {
+                    Some(_) => Some(row.try_get("sort_id")?),
+                    None => None,
+                };
+                Ok((Cursor { key, id }, node(row)?))
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+        Ok(self.assemble(total, entries))
+    }
+
+    fn assemble<T: OutputType>(
+        &self,
+        total: usize,
+        mut entries: Vec<(Cursor, T)>,
+    ) -> CountedConnection<T> {
+        let more = entries.len() > self.limit();
+        entries.truncate(self.limit());
+        let (mut has_previous, has_next) = if self.backwards() {
+            entries.reverse();
+            (more, self.before.is_some())
+        } else {
+            (self.after.is_some(), more)
+        };
+        if let (Some(_), Some(last)) = (self.first, self.last) {
+            if entries.len() > last {
+                entries.drain(..entries.len() - last);
+                has_previous = true;
+            }
+        }
+        let mut connection = Connection::with_additional_fields(
+            has_previous,
+            has_next,
+            TotalCount { total_count: total },
+        );
+        connection.edges.extend(
+            entries
+                .into_iter()
+                .map(|(cursor, node)| Edge::new(cursor, node)),
+        );
+        connection
+    }
+}
+
+/// Escapes a user supplied prefix for `LIKE … ESCAPE '\'`.
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    pattern.push('%');
+    pattern
+}
+
+fn push_date_range<'a>(
+    query: &mut QueryBuilder<'a, Sqlite>,
+    column: &str,
+    after: Option<DateTime<Utc>>,
+    before: Option<DateTime<Utc>>,
+) {
+    if let Some(after) = after {
+        query.push(format!(" AND {} >= ", column)).push_bind(after);
+    }
+    if let Some(before) = before {
+        query.push(format!(" AND {} < ", column)).push_bind(before);
+    }
+}
+
+fn push_name_prefix<'a>(query: &mut QueryBuilder<'a, Sqlite>, prefix: &Option<String>) {
+    if let Some(prefix) = prefix {
+        query
+            .push(" AND name LIKE ")
+            .push_bind(like_prefix(prefix))
+            .push(" ESCAPE '\\'");
+    }
+}
+
+/// Requires every tag, `links` selects the tagged objects from a link table.
+fn push_tags<'a>(query: &mut QueryBuilder<'a, Sqlite>, links: &str, tags: &[Uuid]) {
+    for tag in tags {
+        query
+            .push(format!(" AND uuid IN ({} WHERE tag = ", links))
+            .push_bind(*tag)
+            .push(")");
+    }
+}
+
+#[derive(Debug, Default, InputObject)]
+pub struct ItemFilter {
+    /// Only items directly in this container
+    pub container: Option<Uuid>,
+    /// Only items at this location or any of its sublocations
+    pub location: Option<Uuid>,
+    pub min_quantity: Option<usize>,
+    pub max_quantity: Option<usize>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    /// Only items whose name starts with this, ignoring case
+    pub name_prefix: Option<String>,
+    /// Only items with fewer than their minimum quantity
+    #[graphql(default)]
+    pub low_stock: bool,
+    /// Only items that expire on or before this day
+    pub expires_by: Option<NaiveDate>,
+    /// Only items that have all of these tags
+    #[graphql(default)]
+    pub tags: Vec<Uuid>,
+    /// Only items in this category
+    pub category: Option<Uuid>,
+    /// Only items whose custom fields match all of these
+    #[graphql(default)]
+    pub fields: Vec<FieldFilter>,
+}
+
+/// Condition on the value of a custom field. Items without a value never match.
+#[derive(Debug, InputObject)]
+pub struct FieldFilter {
+    pub field: Uuid,
+    /// Exact value of text, enum and URL fields
+    pub text: Option<String>,
+    pub min_number: Option<f64>,
+    pub max_number: Option<f64>,
+    /// Only dates on or after this one
+    pub date_from: Option<NaiveDate>,
+    /// Only dates on or before this one
+    pub date_to: Option<NaiveDate>,
+    pub boolean: Option<bool>,
+}
+
+impl FieldFilter {
+    fn push<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) {
+        query
+            .push(" AND uuid IN (SELECT item FROM item_fields WHERE field = ")
+            .push_bind(self.field);
+        if let Some(text) = &self.text {
+            query.push(" AND value = ").push_bind(text.clone());
+        }
+        if let Some(min_number) = self.min_number {
+            query.push(" AND value >= ").push_bind(min_number);
+        }
+        if let Some(max_number) = self.max_number {
+            query.push(" AND value <= ").push_bind(max_number);
+        }
+        if let Some(date_from) = self.date_from {
+            query.push(" AND value >= ").push_bind(date_from);
+        }
+        if let Some(date_to) = self.date_to {
+            query.push(" AND value <= ").push_bind(date_to);
+        }
+        if let Some(boolean) = self.boolean {
+            query.push(" AND value = ").push_bind(boolean);
+        }
+        query.push(")");
+    }
+}
+
+impl ItemFilter {
+    /// Appends the conditions to a query that already has a `WHERE` clause.
+    pub fn push<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) {
+        if let Some(container) = self.container {
+            query.push(" AND container = ").push_bind(container);
+        }
+        if let Some(location) = self.location {
+            query
+                .push(" AND container IN (SELECT uuid FROM containers WHERE location IN ")
+                .push(LOCATION_SUBTREE)
+                .push_bind(location)
+                .push(LOCATION_SUBTREE_END)
+                .push(")");
+        }
+        if let Some(min_quantity) = self.min_quantity {
+            query
+                .push(" AND quantity >= ")
+                .push_bind(min_quantity as i64);
+        }
+        if let Some(max_quantity) = self.max_quantity {
+            query
+                .push(" AND quantity <= ")
+                .push_bind(max_quantity as i64);
+        }
+        push_date_range(query, "created", self.created_after, self.created_before);
+        push_date_range(query, "updated", self.updated_after, self.updated_before);
+        push_name_prefix(query, &self.name_prefix);
+        if self.low_stock {
+            query.push(" AND quantity < min_quantity");
+        }
+        if let Some(expires_by) = self.expires_by {
+            query.push(" AND expires_at <= ").push_bind(expires_by);
+        }
+        push_tags(query, "SELECT item FROM item_tags", &self.tags);
+        if let Some(category) = self.category {
+            query.push(" AND category = ").push_bind(category);
+        }
+        for field in &self.fields {
+            field.push(query);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Enum)]
+pub enum ItemSort {
+    #[default]
+    Name,
+    Created,
+    Updated,
+    Quantity,
+}
+
+impl ItemSort {
+    pub fn order(self, descending: bool) -> Order {
+        let key = match self {
+            ItemSort::Name => "name COLLATE NOCASE",
+            ItemSort::Created => "created",
+            ItemSort::Updated => "updated",
+            ItemSort::Quantity => "quantity",
+        };
+        Order::new(key, descending)
+    }
+}
+
+#[derive(Debug, Default, InputObject)]
+pub struct ContainerFilter {
+    /// Only containers at this location or any of its sublocations
+    pub location: Option<Uuid>,
+    /// Only containers directly inside this container
+    pub parent: Option<Uuid>,
+    /// Only containers that aren't inside another container
+    #[graphql(default)]
+    pub top_level: bool,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    /// Only containers whose name starts with this, ignoring case
+    pub name_prefix: Option<String>,
+    /// Only containers that have all of these tags
+    #[graphql(default)]
+    pub tags: Vec<Uuid>,
+}
+
+impl ContainerFilter {
+    /// Appends the conditions to a query that already has a `WHERE` clause.
+    pub fn push<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) {
+        if let Some(location) = self.location {
+            query
+                .push(" AND location IN ")
+                .push(LOCATION_SUBTREE)
+                .push_bind(location)
+                .push(LOCATION_SUBTREE_END);
+        }
+        if let Some(parent) = self.parent {
+            query.push(" AND parent = ").push_bind(parent);
+        }
+        if self.top_level {
+            query.push(" AND parent IS NULL");
+        }
+        push_date_range(query, "created", self.created_after, self.created_before);
+        push_date_range(query, "updated", self.updated_after, self.updated_before);
+        push_name_prefix(query, &self.name_prefix);
+        push_tags(query, "SELECT container FROM container_tags", &self.tags);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Enum)]
+pub enum ContainerSort {
+    #[default]
+    Name,
+    Created,
+    Updated,
+}
+
+impl ContainerSort {
+    pub fn order(self, descending: bool) -> Order {
+        let key = match self {
+            // unnamed containers come first, like they would with NULL
+            ContainerSort::Name => "IFNULL(name, '') COLLATE NOCASE",
+            ContainerSort::Created => "created",
+            ContainerSort::Updated => "updated",
+        };
+        Order::new(key, descending)
+    }
+}
+
+#[derive(Debug, Default, InputObject)]
+pub struct LocationFilter {
+    /// Only locations directly within this location
+    pub parent: Option<Uuid>,
+    /// Only locations that aren't part of another location
+    #[graphql(default)]
+    pub top_level: bool,
+    /// Only locations whose name starts with this, ignoring case
+    pub name_prefix: Option<String>,
+}
+
+impl LocationFilter {
+    /// Appends the conditions to a query that already has a `WHERE` clause.
+    pub fn push<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>) {
+        if let Some(parent) = self.parent {
+            query.push(" AND parent = ").push_bind(parent);
+        }
+        if self.top_level {
+            query.push(" AND parent IS NULL");
+        }
+        push_name_prefix(query, &self.name_prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+        SqlitePool,
+    };
+
+    use super::*;
+
+    fn cursor(key: i64) -> Cursor {
+        Cursor {
+            key: SortKey::Integer(key),
+            id: None,
+        }
+    }
+
+    /// Entries as the query would return them, one more than asked for if there are any.
+    fn entries(keys: impl IntoIterator<Item = i64>) -> Vec<(Cursor, i64)> {
+        keys.into_iter().map(|key| (cursor(key), key)).collect()
+    }
+
+    fn nodes(connection: &CountedConnection<i64>) -> Vec<i64> {
+        connection.edges.iter().map(|edge| edge.node).collect()
+    }
+
+    #[test]
+    fn cursors_survive_encoding() {
+        for cursor in [
+            cursor(-3),
+            Cursor {
+                key: SortKey::Text("2026-10-16 08:00:00".to_owned()),
+                id: Some(Uuid::new_v4()),
+            },
+            Cursor {
+                key: SortKey::Text("42".to_owned()),
+                id: None,
+            },
+        ] {
+            assert_eq!(
+                Cursor::decode_cursor(&cursor.encode_cursor()).unwrap(),
+                cursor
+            );
+        }
+        assert!(Cursor::decode_cursor("3").is_err());
+    }
+
+    #[test]
+    fn pages_forwards() {
+        let order = Order::unique("id", false);
+        let page = Page::new(order, None, None, Some(2), None);
+        let connection = page.assemble(5, entries([1, 2, 3]));
+        assert_eq!(nodes(&connection), [1, 2]);
+        assert!(!connection.has_previous_page);
+        assert!(connection.has_next_page);
+        assert_eq!(connection.additional_fields.total_count, 5);
+
+        let page = Page::new(order, Some(cursor(3)), None, Some(2), None);
+        let connection = page.assemble(5, entries([4, 5]));
+        assert_eq!(nodes(&connection), [4, 5]);
+        assert!(connection.has_previous_page);
+        assert!(!connection.has_next_page);
+    }
+
+    #[test]
+    fn pages_backwards() {
+        let order = Order::unique("id", false);
+        // the query runs in reverse
+        let page = Page::new(order, None, None, None, Some(2));
+        let connection = page.assemble(5, entries([5, 4, 3]));
+        assert_eq!(nodes(&connection), [4, 5]);
+        assert!(connection.has_previous_page);
+        assert!(!connection.has_next_page);
+
+        let page = Page::new(order, None, Some(cursor(3)), None, Some(2));
+        let connection = page.assemble(5, entries([2, 1]));
+        assert_eq!(nodes(&connection), [1, 2]);
+        assert!(!connection.has_previous_page);
+        assert!(connection.has_next_page);
+    }
+
+    #[test]
+    fn takes_last_of_first() {
+        let page = Page::new(Order::unique("id", false), None, None, Some(3), Some(2));
+        let connection = page.assemble(5, entries([1, 2, 3, 4]));
+        assert_eq!(nodes(&connection), [2, 3]);
+        assert!(connection.has_previous_page);
+        assert!(connection.has_next_page);
+    }
+
+    #[test]
+    fn limits_page_size() {
+        let page = Page::new(Order::unique("id", false), None, None, Some(1000), None);
+        let connection = page.assemble(200, entries(0..200));
+        assert_eq!(connection.edges.len(), MAX_PAGE_SIZE);
+        assert!(connection.has_next_page);
+        let page = Page::new(Order::unique("id", false), None, None, None, None);
+        assert_eq!(
+            page.assemble(200, entries(0..200)).edges.len(),
+            MAX_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn compares_with_the_cursor_in_sort_order() {
+        let after = Cursor {
+            key: SortKey::Text("b".to_owned()),
+            id: Some(Uuid::nil()),
+        };
+        let page = Page::new(Order::new("name", true), Some(after), None, None, Some(2));
+        let mut query = QueryBuilder::new("SELECT name");
+        page.push_columns(&mut query);
+        query.push(" FROM entries WHERE 1");
+        page.push_range(&mut query).unwrap();
+        assert_eq!(
+            query.sql(),
+            "SELECT name, name AS sort_key, uuid AS sort_id FROM entries WHERE 1 \
+            AND (name, uuid) < (?, ?) ORDER BY name ASC, uuid ASC LIMIT ?"
+        );
+
+        let missing_id = Page::new(Order::new("name", false), Some(cursor(1)), None, None, None);
+        assert!(missing_id
+            .push_range(&mut QueryBuilder::new("SELECT name FROM entries WHERE 1"))
+            .is_err());
+    }
+
+    async fn database(names: &[&str]) -> SqlitePool {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE entries (uuid BLOB PRIMARY KEY NOT NULL, name TEXT NOT NULL)")
+            .execute(&db)
+            .await
+            .unwrap();
+        for name in names {
+            insert(&db, name).await;
+        }
+        db
+    }
+
+    async fn insert(db: &SqlitePool, name: &str) {
+        sqlx::query("INSERT INTO entries (uuid, name) VALUES (?, ?)")
+            .bind(Uuid::new_v4())
+            .bind(name)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn fetch(db: &SqlitePool, page: &Page) -> CountedConnection<String> {
+        let mut query = QueryBuilder::new("SELECT name");
+        page.push_columns(&mut query);
+        query.push(" FROM entries WHERE 1");
+        page.push_range(&mut query).unwrap();
+        let rows = query.build().fetch_all(db).await.unwrap();
+        page.connection(0, rows, |row| row.try_get("name")).unwrap()
+    }
+
+    /// Names and ids in the order the list should have.
+    async fn sorted(db: &SqlitePool) -> Vec<(String, Uuid)> {
+        let mut entries: Vec<(String, Uuid)> = sqlx::query_as("SELECT name, uuid FROM entries")
+            .fetch_all(db)
+            .await
+            .unwrap();
+        entries.sort_by_key(|(name, id)| (name.to_lowercase(), *id));
+        entries
+    }
+
+    fn edges(connection: &CountedConnection<String>) -> Vec<(String, Uuid)> {
+        connection
+            .edges
+            .iter()
+            .map(|edge| (edge.node.clone(), edge.cursor.id.unwrap()))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn pages_through_equal_keys_without_gaps() {
+        let db = database(&["b", "A", "a", "c", "B", "d", "a"]).await;
+        let order = Order::new("name COLLATE NOCASE", false);
+
+        let mut forwards = Vec::new();
+        let mut after = None;
+        loop {
+            let connection = fetch(&db, &Page::new(order, after, None, Some(3), None)).await;
+            forwards.extend(edges(&connection));
+            after = connection.edges.last().map(|edge| edge.cursor.clone());
+            if !connection.has_next_page {
+                break;
+            }
+        }
+        assert_eq!(forwards, sorted(&db).await);
+
+        let mut backwards = Vec::new();
+        let mut before = None;
+        loop {
+            let connection = fetch(&db, &Page::new(order, None, before, None, Some(2))).await;
+            backwards.splice(0..0, edges(&connection));
+            before = connection.edges.first().map(|edge| edge.cursor.clone());
+            if !connection.has_previous_page {
+                break;
+            }
+        }
+        assert_eq!(backwards, forwards);
+    }
+
+    #[actix_web::test]
+    async fn continues_after_the_cursor_when_entries_are_added() {
+        let db = database(&["b", "d", "f", "h"]).await;
+        let order = Order::new("name COLLATE NOCASE", false);
+        let first = fetch(&db, &Page::new(order, None, None, Some(2), None)).await;
+        assert_eq!(first.edges[1].node, "d");
+
+        // an offset would now return "d" again
+        insert(&db, "a").await;
+        let after = first.edges[1].cursor.clone();
+        let second = fetch(&db, &Page::new(order, Some(after), None, Some(2), None)).await;
+        let names: Vec<_> = second.edges.iter().map(|edge| edge.node.as_str()).collect();
+        assert_eq!(names, ["f", "h"]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Batched lookups of related objects, so that lists resolve their relations with one query
+//! instead of one per entry.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::dataloader::Loader;
+use sqlx::{sqlite::SqliteRow, Executor, FromRow, QueryBuilder, Sqlite};
+use uuid::Uuid;
+
+use crate::{
+    custom_fields::{self, Category, CategoryRow, FieldValue, FieldValueRow},
+    gallery::{self, Image, ImageRow},
+    schema::{Container, ContainerRow, Item, ItemRow, Location, LocationRow},
+    tags::{self, Tag, TaggedRow},
+    MetadataDatabase,
+};
+
+pub struct LocationLoader(pub MetadataDatabase);
+pub struct ContainerLoader(pub MetadataDatabase);
+/// Sublocations by location
+pub struct LocationChildrenLoader(pub MetadataDatabase);
+/// Nested containers by container
+pub struct ContainerChildrenLoader(pub MetadataDatabase);
+pub struct ItemLoader(pub MetadataDatabase);
+pub struct CategoryLoader(pub MetadataDatabase);
+/// Custom field values by item
+pub struct ItemFieldsLoader(pub MetadataDatabase);
+/// Tags by item
+pub struct ItemTagsLoader(pub MetadataDatabase);
+/// Tags by container
+pub struct ContainerTagsLoader(pub MetadataDatabase);
+/// Image galleries by item
+pub struct ItemImagesLoader(pub MetadataDatabase);
+/// Image galleries by container
+pub struct ContainerImagesLoader(pub MetadataDatabase);
+
+// trashed objects are left out
+pub(crate) const LOCATIONS: &str =
+    "SELECT uuid, name, description, parent FROM locations WHERE deleted_at IS NULL AND uuid IN";
+pub(crate) const CONTAINERS: &str =
+    "SELECT uuid, created, updated, name, location, parent FROM containers WHERE deleted_at IS NULL AND uuid IN";
+const LOCATION_CHILDREN: &str =
+    "SELECT uuid, name, description, parent FROM locations WHERE deleted_at IS NULL AND parent IN";
+const CONTAINER_CHILDREN: &str =
+    "SELECT uuid, created, updated, name, location, parent FROM containers WHERE deleted_at IS NULL AND parent IN";
+pub(crate) const ITEMS: &str =
+    "SELECT uuid, created, updated, name, description, quantity, container, category,
+        purchased_on, vendor, price, currency, serial_number, model_number, warranty_until,
+        min_quantity, expires_at FROM items WHERE deleted_at IS NULL AND uuid IN";
+const CATEGORIES: &str = "SELECT uuid, name, description FROM categories WHERE uuid IN";
+pub(crate) const ITEM_FIELDS: &str =
+    "SELECT v.item, CAST(v.value AS TEXT) as value, f.rowid as position, f.uuid, f.category, f.name, f.kind, f.unit, f.options, f.required FROM item_fields v JOIN field_definitions f ON f.uuid = v.field WHERE v.item IN";
+pub(crate) const ITEM_TAGS: &str =
+    "SELECT l.item as owner, t.uuid, t.name, t.color FROM item_tags l JOIN tags t ON t.uuid = l.tag WHERE l.item IN";
+pub(crate) const CONTAINER_TAGS: &str =
+    "SELECT l.container as owner, t.uuid, t.name, t.color FROM container_tags l JOIN tags t ON t.uuid = l.tag WHERE l.container IN";
+pub(crate) const ITEM_IMAGES: &str =
+    "SELECT i.uuid, i.item as owner, i.content_type, i.caption, i.position, i.is_primary, i.uploaded, u.username as uploaded_by, i.taken_at FROM images i LEFT JOIN users u ON u.uuid = i.uploader WHERE i.item IN";
+pub(crate) const CONTAINER_IMAGES: &str =
+    "SELECT i.uuid, i.container as owner, i.content_type, i.caption, i.position, i.is_primary, i.uploaded, u.username as uploaded_by, i.taken_at FROM images i LEFT JOIN users u ON u.uuid = i.uploader WHERE i.container IN";
+
+/// Runs `select` (ending in `IN`) for all keys.
+pub(crate) async fn fetch_all<'c, R, E>(
+    db: E,
+    select: &str,
+    keys: &[Uuid],
+) -> Result<Vec<R>, sqlx::Error>
+where
+    R: for<'r> FromRow<'r, SqliteRow> + Send + Unpin,
+    E: Executor<'c, Database = Sqlite>,
+{
+    let mut query = QueryBuilder::new(select);
+    query.push(" (");
+    let mut separated = query.separated(", ");
+    for key in keys {
+        separated.push_bind(*key);
+    }
+    separated.push_unseparated(")");
+    query.build_query_as().fetch_all(db).await
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for LocationLoader {
+    type Value = Location;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Location>, Self::Error> {
+        let rows: Vec<LocationRow> = fetch_all(&self.0, LOCATIONS, keys).await?;
+        Ok(rows
+            .into_iter()
+            .map(Location::from)
+            .map(|location| (location.id, location))
+            .collect())
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ContainerLoader {
+    type Value = Container;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Container>, Self::Error> {
+        let rows: Vec<ContainerRow> = fetch_all(&self.0, CONTAINERS, keys).await?;
+        Ok(rows
+            .into_iter()
+            .map(Container::from)
+            .map(|container| (container.id, container))
+            .collect())
+    }
+}
+
+/// Groups children by their parent.
+fn by_parent<T>(
+    children: impl IntoIterator<Item = T>,
+    parent: fn(&T) -> Option<Uuid>,
+) -> HashMap<Uuid, Vec<T>> {
+    let mut groups: HashMap<Uuid, Vec<T>> = HashMap::new();
+    for child in children {
+        if let Some(parent) = parent(&child) {
+            groups.entry(parent).or_default().push(child);
+        }
+    }
+    groups
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for LocationChildrenLoader {
+    type Value = Vec<Location>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Location>>, Self::Error> {
+        let rows: Vec<LocationRow> = fetch_all(&self.0, LOCATION_CHILDREN, keys).await?;
+        Ok(by_parent(
+            rows.into_iter().map(Location::from),
+            |location| location.parent_id,
+        ))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ContainerChildrenLoader {
+    type Value = Vec<Container>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Container>>, Self::Error> {
+        let rows: Vec<ContainerRow> = fetch_all(&self.0, CONTAINER_CHILDREN, keys).await?;
+        Ok(by_parent(
+            rows.into_iter().map(Container::from),
+            |container| container.parent_id,
+        ))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ItemLoader {
+    type Value = Item;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Item>, Self::Error> {
+        let rows: Vec<ItemRow> = fetch_all(&self.0, ITEMS, keys).await?;
+        Ok(rows
+            .into_iter()
+            .map(Item::from)
+            .map(|item| (item.id, item))
+            .collect())
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ItemTagsLoader {
+    type Value = Vec<Tag>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Tag>>, Self::Error> {
+        let rows: Vec<TaggedRow> = fetch_all(&self.0, ITEM_TAGS, keys).await?;
+        Ok(tags::group(rows))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ContainerTagsLoader {
+    type Value = Vec<Tag>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Tag>>, Self::Error> {
+        let rows: Vec<TaggedRow> = fetch_all(&self.0, CONTAINER_TAGS, keys).await?;
+        Ok(tags::group(rows))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ItemImagesLoader {
+    type Value = Vec<Image>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Image>>, Self::Error> {
+        let rows: Vec<ImageRow> = fetch_all(&self.0, ITEM_IMAGES, keys).await?;
+        Ok(gallery::group(rows))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ContainerImagesLoader {
+    type Value = Vec<Image>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Image>>, Self::Error> {
+        let rows: Vec<ImageRow> = fetch_all(&self.0, CONTAINER_IMAGES, keys).await?;
+        Ok(gallery::group(rows))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for CategoryLoader {
+    type Value = Category;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Category>, Self::Error> {
+        let rows: Vec<CategoryRow> = fetch_all(&self.0, CATEGORIES, keys).await?;
+        Ok(rows
+            .into_iter()
+            .map(Category::from)
+            .map(|category| (category.id, category))
+            .collect())
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Loader<Uuid> for ItemFieldsLoader {
+    type Value = Vec<FieldValue>;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<FieldValue>>, Self::Error> {
+        let rows: Vec<FieldValueRow> = fetch_all(&self.0, ITEM_FIELDS, keys).await?;
+        Ok(custom_fields::group(rows))
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, schema::LOGIN_ATTEMPT_TYPE, FileDatabase};
+
+/// Failed login counter, stored per client address, per account and once for all logins.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FailedLogins {
+    count: u32,
+    last: Option<DateTime<Utc>>,
+}
+
+/// How one of the counters backs off
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    free_attempts: u32,
+    base_delay_secs: u64,
+    max_delay_secs: u64,
+}
+
+impl Limits {
+    fn per_client(config: &config::LoginBackoff) -> Self {
+        Limits {
+            free_attempts: config.free_attempts,
+            base_delay_secs: config.base_delay_secs,
+            max_delay_secs: config.max_delay_secs,
+        }
+    }
+
+    fn per_account(config: &config::LoginBackoff) -> Self {
+        Limits {
+            free_attempts: config.account_free_attempts,
+            ..Self::per_client(config)
+        }
+    }
+
+    fn global(config: &config::LoginBackoff) -> Self {
+        Limits {
+            free_attempts: config.global.free_attempts,
+            base_delay_secs: config.global.base_delay_secs,
+            max_delay_secs: config.global.max_delay_secs,
+        }
+    }
+
+    /// Limits of the counter stored under `key`
+    fn of_key(key: &[u8], config: &config::LoginBackoff) -> Self {
+        if key == global_key().as_slice() {
+            Self::global(config)
+        } else {
+            Self::per_client(config)
+        }
+    }
+}
+
+impl FailedLogins {
+    fn parse(value: &[u8]) -> Self {
+        serde_json::from_slice(value).unwrap_or_default()
+    }
+
+    /// Whether the counter has been quiet for the maximum delay, so that it starts over.
+    fn is_stale(&self, limits: Limits) -> bool {
+        self.last
+            .is_none_or(|last| Utc::now() - last > Duration::seconds(limits.max_delay_secs as _))
+    }
+
+    fn load(db: &FileDatabase, key: &[u8], limits: Limits) -> Self {
+        let failed = db
+            .get(key)
+            .ok()
+            .flatten()
+            .map(|value| Self::parse(&value))
+            .unwrap_or_default();
+        if failed.is_stale(limits) {
+            Self::default()
+        } else {
+            failed
+        }
+    }
+
+    fn store(&self, db: &FileDatabase, key: &[u8]) {
+        if let Ok(value) = serde_json::to_vec(self) {
+            db.put(key, value).ok();
+        }
+    }
+
+    /// Time until the next attempt is allowed, doubling with every failure beyond the free ones.
+    fn retry_after(&self, limits: Limits) -> Option<Duration> {
+        let last = self.last?;
+        let exponent = self.count.checked_sub(limits.free_attempts)?;
+        let delay = limits
+            .base_delay_secs
+            .saturating_mul(1u64.checked_shl(exponent).unwrap_or(u64::MAX))
+            .min(limits.max_delay_secs);
+        let remaining = last + Duration::seconds(delay as _) - Utc::now();
+        (remaining > Duration::zero()).then_some(remaining)
+    }
+}
+
+fn client_key(ip: Option<IpAddr>) -> Vec<u8> {
+    let ip = ip.map(|ip| ip.to_string()).unwrap_or_default();
+    std::iter::once(LOGIN_ATTEMPT_TYPE)
+        .chain(b"ip:".iter().copied())
+        .chain(ip.bytes())
+        .collect()
+}
+
+fn account_key(username: &str) -> Vec<u8> {
+    std::iter::once(LOGIN_ATTEMPT_TYPE)
+        .chain(b"user:".iter().copied())
+        .chain(username.bytes())
+        .collect()
+}
+
+fn global_key() -> Vec<u8> {
+    std::iter::once(LOGIN_ATTEMPT_TYPE)
+        .chain(b"global".iter().copied())
+        .collect()
+}
+
+/// Held while checking and counting an attempt, so that concurrent attempts can't all pass the
+/// check before any of them is counted.
+static ATTEMPTS: Mutex<()> = Mutex::new(());
+
+/// Counts a login attempt as failed before the password is even checked, unless the client, the
+/// account or logins as a whole have to wait first. Returns how long then. A successful login
+/// takes the counts of the client and the account back through `record_success`.
+pub fn begin_attempt(
+    db: &FileDatabase,
+    ip: Option<IpAddr>,
+    username: &str,
+    config: &config::LoginBackoff,
+) -> Result<(), Duration> {
+    let _lock = ATTEMPTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let counters = [
+        (client_key(ip), Limits::per_client(config)),
+        (account_key(username), Limits::per_account(config)),
+        (global_key(), Limits::global(config)),
+    ]
+    .map(|(key, limits)| {
+        let failed = FailedLogins::load(db, &key, limits);
+        (key, limits, failed)
+    });
+    if let Some(wait) = counters
+        .iter()
+        .filter_map(|(_, limits, failed)| failed.retry_after(*limits))
+        .max()
+    {
+        return Err(wait);
+    }
+
+    let now = Utc::now();
+    for (key, _, mut failed) in counters {
+        failed.count = failed.count.saturating_add(1);
+        failed.last = Some(now);
+        failed.store(db, &key);
+    }
+    Ok(())
+}
+
+/// Takes back the counts of a successful login. The global one stays, or any account could be
+/// used to reset it.
+pub fn record_success(db: &FileDatabase, ip: Option<IpAddr>, username: &str) {
+    let _lock = ATTEMPTS.lock().unwrap_or_else(PoisonError::into_inner);
+    db.delete(client_key(ip)).ok();
+    db.delete(account_key(username)).ok();
+}
+
+/// Deletes counters that would start over anyway, so that every address and user name ever
+/// tried doesn't stay in the database. Returns how many.
+pub fn sweep(db: &FileDatabase, config: &config::LoginBackoff) -> usize {
+    let _lock = ATTEMPTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let stale: Vec<_> = db
+        .prefix_iterator([LOGIN_ATTEMPT_TYPE])
+        .take_while(|(key, _)| key.first() == Some(&LOGIN_ATTEMPT_TYPE))
+        .filter(|(key, value)| FailedLogins::parse(value).is_stale(Limits::of_key(key, config)))
+        .map(|(key, _)| key)
+        .collect();
+    stale
+        .into_iter()
+        .filter(|key| db.delete(key).is_ok())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            free_attempts: 3,
+            base_delay_secs: 2,
+            max_delay_secs: 60,
+        }
+    }
+
+    fn failed(count: u32, ago_secs: i64) -> FailedLogins {
+        FailedLogins {
+            count,
+            last: Some(Utc::now() - Duration::seconds(ago_secs)),
+        }
+    }
+
+    /// A file database in a temporary directory, removed again on drop
+    struct TempDb {
+        db: FileDatabase,
+        path: std::path::PathBuf,
+    }
+
+    impl TempDb {
+        fn new() -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("homebox-login-attempts-{}", uuid::Uuid::new_v4()));
+            TempDb {
+                db: FileDatabase::open_default(&path).unwrap(),
+                path,
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.path).ok();
+        }
+    }
+
+    #[test]
+    fn free_attempts_need_no_waiting() {
+        let limits = limits();
+        assert_eq!(FailedLogins::default().retry_after(limits), None);
+        assert_eq!(failed(2, 0).retry_after(limits), None);
+    }
+
+    #[test]
+    fn delay_doubles_with_every_further_failure() {
+        let limits = limits();
+        for (count, delay) in [(3, 2), (4, 4), (5, 8), (6, 16)] {
+            let wait = failed(count, 0).retry_after(limits).unwrap();
+            assert!(wait <= Duration::seconds(delay), "{} failures", count);
+            assert!(wait > Duration::seconds(delay - 1), "{} failures", count);
+        }
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let limits = limits();
+        for count in [10, 64, 65, u32::MAX] {
+            let wait = failed(count, 0).retry_after(limits).unwrap();
+            assert!(wait <= Duration::seconds(60), "{} failures", count);
+            assert!(wait > Duration::seconds(59), "{} failures", count);
+        }
+    }
+
+    #[test]
+    fn delay_counts_from_the_last_failure() {
+        let limits = limits();
+        assert_eq!(failed(4, 4).retry_after(limits), None);
+        let wait = failed(5, 4).retry_after(limits).unwrap();
+        assert!(wait <= Duration::seconds(4) && wait > Duration::seconds(3));
+    }
+
+    #[test]
+    fn global_counter_spans_addresses_and_accounts() {
+        let temp = TempDb::new();
+        let config = config::LoginBackoff {
+            global: config::GlobalBackoff {
+                free_attempts: 2,
+                base_delay_secs: 30,
+                max_delay_secs: 60,
+            },
+            ..Default::default()
+        };
+        for n in 1..=2u8 {
+            let ip = Some(IpAddr::from([192, 0, 2, n]));
+            assert!(begin_attempt(&temp.db, ip, &format!("user{}", n), &config).is_ok());
+        }
+        let ip = Some(IpAddr::from([192, 0, 2, 3]));
+        let wait = begin_attempt(&temp.db, ip, "user3", &config).unwrap_err();
+        assert!(wait > Duration::seconds(29) && wait <= Duration::seconds(30));
+
+        // a successful login doesn't take the global count back
+        record_success(&temp.db, ip, "user3");
+        assert!(begin_attempt(&temp.db, ip, "user3", &config).is_err());
+    }
+
+    #[test]
+    fn sweep_deletes_only_stale_counters() {
+        let temp = TempDb::new();
+        let config = config::LoginBackoff::default();
+        let stale = failed(5, config.max_delay_secs as i64 + 1);
+        stale.store(&temp.db, &client_key(Some(IpAddr::from([192, 0, 2, 1]))));
+        stale.store(&temp.db, &account_key("nobody"));
+        failed(5, 0).store(&temp.db, &account_key("somebody"));
+        // the global counter starts over sooner
+        failed(5, config.global.max_delay_secs as i64 + 1).store(&temp.db, &global_key());
+
+        assert_eq!(sweep(&temp.db, &config), 3);
+        assert!(temp.db.get(account_key("somebody")).unwrap().is_some());
+        assert_eq!(sweep(&temp.db, &config), 0);
+    }
+}
@@ -16,19 +16,40 @@ use actix_web::{
     web::{self, Data},
     App, HttpResponse, HttpServer, Responder,
 };
-use async_graphql::{futures_util::lock::Mutex, http::graphiql_source, EmptySubscription, Schema};
-use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
-use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqliteConnection};
+use async_graphql::{dataloader::DataLoader, http::graphiql_source, Data as GraphQLData, Schema};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    SqlitePool,
+};
 use structopt::StructOpt;
 
+mod api_tokens;
+mod changes;
 mod config;
+mod custom_fields;
+mod gallery;
 use config::Config;
+mod history;
+mod http_cache;
+mod image_formats;
+mod image_metadata;
 mod images;
+mod listing;
+mod loaders;
+mod login_attempts;
+mod notifications;
+mod oidc;
+mod purchases;
 mod schema;
+mod tags;
+mod thumbnails;
+mod trash;
 mod user_session;
+mod users;
 
 pub type FileDatabase = rocksdb::DBWithThreadMode<rocksdb::MultiThreaded>;
-pub type MetadataDatabase = Arc<Mutex<SqliteConnection>>;
+pub type MetadataDatabase = SqlitePool;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "homebox-server", about = "Backend for Homebox")]
@@ -92,53 +113,162 @@ async fn main() -> std::io::Result<()> {
         .metadata_database
         .as_deref()
         .unwrap_or(&config.database.metadata);
-    let mut metadata_db = SqliteConnectOptions::from_str(metadata_database_path)
+    let metadata_options = SqliteConnectOptions::from_str(metadata_database_path)
         .unwrap()
         .create_if_missing(true)
         .foreign_keys(true)
-        .connect()
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(std::time::Duration::from_secs(
+            config.database.busy_timeout_secs,
+        ));
+    let metadata_db = SqlitePoolOptions::new()
+        .max_connections(config.database.pool_size)
+        .connect_with(metadata_options)
         .await
         .unwrap();
     sqlx::migrate!()
-        .run(&mut metadata_db)
+        .run(&metadata_db)
         .await
         .expect("Failed applying sqlite migrations");
-    let metadata_db = Arc::new(Mutex::new(metadata_db));
-
-    let schema = Schema::build(schema::QueryRoot, schema::MutationRoot, EmptySubscription)
-        .data(metadata_db.clone())
-        .finish();
+    users::bootstrap_admin(
+        &mut metadata_db
+            .acquire()
+            .await
+            .expect("Failed connecting to the metadata database"),
+        &config,
+    )
+    .await
+    .expect("Failed creating initial user");
+    match gallery::convert_legacy_images(&file_db, &metadata_db).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("Moved {} images into galleries.", count),
+        Err(err) => log::error!("Failed moving images into galleries: {}", err),
+    }
 
     let config = Arc::new(config);
+    let broker = Arc::new(changes::Broker::new(metadata_db.clone()));
+    let schema = Schema::build(
+        schema::QueryRoot,
+        schema::MutationRoot,
+        schema::SubscriptionRoot,
+    )
+    .data(metadata_db.clone())
+    .data(file_db.clone())
+    .data(broker.clone())
+    .data(config.clone())
+    .data(DataLoader::new(
+        loaders::LocationLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ContainerLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::LocationChildrenLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ContainerChildrenLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ItemLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::CategoryLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ItemFieldsLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ItemImagesLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ContainerImagesLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ItemTagsLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .data(DataLoader::new(
+        loaders::ContainerTagsLoader(metadata_db.clone()),
+        actix_web::rt::spawn,
+    ))
+    .finish();
+
     let inner_config = config.clone();
     let cookie_key = get_secret_key(&config.auth.cookie_storage)?;
+    let session_ttl = Duration::days(config.auth.session_max_age_days.into());
+    let oidc_provider = config
+        .auth
+        .oidc
+        .clone()
+        .map(|oidc| Arc::new(oidc::Provider::new(oidc)));
+    user_session::init_login_page(oidc_provider.is_some());
+    // the identity provider redirects back cross-site, which wouldn't carry strict cookies
+    let same_site = if oidc_provider.is_some() {
+        SameSite::Lax
+    } else {
+        SameSite::Strict
+    };
+    actix_web::rt::spawn(user_session::sweep_sessions(
+        file_db.clone(),
+        config.clone(),
+    ));
+    actix_web::rt::spawn(trash::sweep_trash(
+        file_db.clone(),
+        metadata_db.clone(),
+        config.clone(),
+    ));
+    actix_web::rt::spawn(notifications::watch_stock(
+        metadata_db.clone(),
+        config.clone(),
+    ));
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(schema.clone()))
             .app_data(Data::new(file_db.clone()))
+            .app_data(Data::new(metadata_db.clone()))
             .app_data(Data::new(inner_config.clone()))
+            .app_data(Data::new(oidc_provider.clone()))
+            .app_data(Data::new(broker.clone()))
             .wrap(
                 SessionMiddleware::builder(CookieSessionStore::default(), cookie_key.clone())
                     .cookie_secure(false)
                     .cookie_path("/".to_owned())
                     .cookie_http_only(true)
-                    .cookie_same_site(SameSite::Strict)
-                    .session_lifecycle(
-                        PersistentSession::default().session_ttl(Duration::days(365)),
-                    )
+                    .cookie_same_site(same_site)
+                    .session_lifecycle(PersistentSession::default().session_ttl(session_ttl))
                     .build(),
             )
             .service(user_session::login)
             .service(user_session::logout)
+            .service(oidc::login_oidc)
+            .service(oidc::oidc_callback)
             .service(playground)
             .service(gql)
+            .service(gql_subscription)
             .service(gql_sdl)
+            .service(images::list_container_images)
             .service(images::upload_container_image)
-            .service(images::fetch_container_image)
-            .service(images::delete_container_image)
+            .service(images::reorder_container_images)
+            .service(images::list_item_images)
             .service(images::upload_item_image)
+            .service(images::reorder_item_images)
+            .service(images::fetch_image)
+            .service(images::fetch_container_image)
             .service(images::fetch_item_image)
-            .service(images::delete_item_image)
+            .service(images::update_image)
+            .service(images::delete_image)
+            .service(images::upload_location_image)
+            .service(images::fetch_location_image)
+            .service(images::delete_location_image)
             .service(images::barcode_container)
             .service(images::barcode_item)
     })
@@ -152,29 +282,59 @@ async fn main() -> std::io::Result<()> {
 }
 
 #[get("/")]
-pub async fn playground(session: Session, db: web::Data<Arc<FileDatabase>>) -> HttpResponse {
-    user_session::verify(&session, &db)
-        .err()
-        .unwrap_or_else(|| {
-            HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(graphiql_source("/api/v1", None))
-        })
+pub async fn playground(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    req: actix_web::HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    user_session::verify(&req, &session, &db, &metadata, &config).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(graphiql_source("/api/v1", Some("/api/v1/ws"))))
 }
 
 #[post("/api/v1")]
 pub async fn gql(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
     schema: web::Data<schema::HomeboxSchema>,
     req: GraphQLRequest,
     actix_req: actix_web::HttpRequest,
-) -> HttpResponse {
-    if let Err(response) = user_session::verify(&session, &db) {
-        response
-    } else {
-        GraphQLResponse::from(schema.execute(req.into_inner()).await).respond_to(&actix_req)
-    }
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&actix_req, &session, &db, &metadata, &config).await?;
+    Ok(GraphQLResponse::from(
+        schema
+            .execute(req.into_inner().data(user).data(credential))
+            .await,
+    )
+    .respond_to(&actix_req))
+}
+
+/// Serves subscriptions over graphql-ws, authenticated like any other request when connecting.
+/// Subscriptions end once that session or API token is no longer valid.
+#[get("/api/v1/ws")]
+pub async fn gql_subscription(
+    session: Session,
+    db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
+    config: web::Data<Arc<Config>>,
+    schema: web::Data<schema::HomeboxSchema>,
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user, credential) =
+        user_session::authenticate(&req, &session, &db, &metadata, &config).await?;
+    let mut data = GraphQLData::default();
+    data.insert(user);
+    data.insert(credential);
+    GraphQLSubscription::new(schema.as_ref().clone())
+        .with_data(data)
+        .start(&req, payload)
 }
 
 #[get("/sdl")]
@@ -0,0 +1,517 @@
+//! Periodic checks for items that are running low or about to expire, reported to the sinks
+//! configured in `notifications`.
+
+use std::{collections::HashSet, sync::Arc, time::Duration as StdDuration};
+
+use anyhow::Error;
+use chrono::{Duration, NaiveDate, Utc};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, Email, Notifications, Ntfy, Sink, SmtpTls, Webhook},
+    MetadataDatabase,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Fewer left than the item's minimum quantity
+    LowStock,
+    /// Expires within the warning period
+    Expiring,
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub item: Uuid,
+    pub name: String,
+    pub kind: AlertKind,
+    pub quantity: i64,
+    pub min_quantity: Option<i64>,
+    pub expires_at: Option<NaiveDate>,
+}
+
+impl Alert {
+    fn describe(&self) -> String {
+        match self.kind {
+            AlertKind::LowStock => format!(
+                "{}: only {} left, at least {} wanted",
+                self.name,
+                self.quantity,
+                self.min_quantity.unwrap_or_default()
+            ),
+            AlertKind::Expiring => {
+                format!("{}: expires on {}", self.name, self.expires_at.unwrap())
+            }
+            AlertKind::Expired => format!("{}: expired on {}", self.name, self.expires_at.unwrap()),
+        }
+    }
+}
+
+fn summary(alerts: &[Alert]) -> String {
+    alerts
+        .iter()
+        .map(Alert::describe)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Everything that currently needs attention.
+async fn current_alerts(db: &MetadataDatabase, warning_days: u32) -> Result<Vec<Alert>, Error> {
+    let today = Utc::today().naive_utc();
+    let warn_until = today + Duration::days(warning_days.into());
+    let rows = sqlx::query!(
+        r#"SELECT uuid, name, quantity, min_quantity, expires_at as "expires_at: NaiveDate"
+        FROM items WHERE deleted_at IS NULL
+            AND (quantity < min_quantity OR expires_at <= ?)
+        ORDER BY name COLLATE NOCASE"#,
+        warn_until
+    )
+    .fetch_all(db)
+    .await?;
+    let mut alerts = Vec::new();
+    for row in rows {
+        let alert = |kind| Alert {
+            item: Uuid::from_slice(&row.uuid).unwrap(),
+            name: row.name.clone(),
+            kind,
+            quantity: row.quantity,
+            min_quantity: row.min_quantity,
+            expires_at: row.expires_at,
+        };
+        if row
+            .min_quantity
+            .map_or(false, |min_quantity| row.quantity < min_quantity)
+        {
+            alerts.push(alert(AlertKind::LowStock));
+        }
+        match row.expires_at {
+            Some(expires_at) if expires_at < today => alerts.push(alert(AlertKind::Expired)),
+            Some(expires_at) if expires_at <= warn_until => alerts.push(alert(AlertKind::Expiring)),
+            _ => {}
+        }
+    }
+    Ok(alerts)
+}
+
+async fn send_email(email: &Email, alerts: &[Alert]) -> Result<(), Error> {
+    let builder = match email.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&email.host),
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email.host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&email.host)?,
+    };
+    let builder = match email.port {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+    let builder = match (&email.username, &email.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+    let mut message = Message::builder()
+        .from(email.from.parse::<Mailbox>()?)
+        .subject(format!("Homebox: {} items need attention", alerts.len()));
+    for to in &email.to {
+        message = message.to(to.parse::<Mailbox>()?);
+    }
+    builder.build().send(message.body(summary(alerts))?).await?;
+    Ok(())
+}
+
+async fn send_webhook(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    alerts: &[Alert],
+) -> Result<(), Error> {
+    let mut request = client
+        .post(&webhook.url)
+        .json(&serde_json::json!({ "alerts": alerts }));
+    for (name, value) in &webhook.headers {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn send_ntfy(client: &reqwest::Client, ntfy: &Ntfy, alerts: &[Alert]) -> Result<(), Error> {
+    let mut request = client
+        .post(&ntfy.url)
+        .header("Title", format!("{} items need attention", alerts.len()))
+        .header("Tags", "package")
+        .body(summary(alerts));
+    if let Some(priority) = ntfy.priority {
+        request = request.header("Priority", priority.to_string());
+    }
+    if let Some(token) = &ntfy.token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn send(client: &reqwest::Client, sink: &Sink, alerts: &[Alert]) -> Result<(), Error> {
+    match sink {
+        Sink::Email(email) => send_email(email, alerts).await,
+        Sink::Webhook(webhook) => send_webhook(client, webhook, alerts).await,
+        Sink::Ntfy(ntfy) => send_ntfy(client, ntfy, alerts).await,
+    }
+}
+
+/// Sends the alerts that haven't been sent yet and forgets those that no longer apply, so they
+/// are sent again when they come back. Returns the number of alerts sent.
+pub async fn check(
+    client: &reqwest::Client,
+    db: &MetadataDatabase,
+    config: &Notifications,
+) -> Result<usize, Error> {
+    let alerts = current_alerts(db, config.expiry_warning_days).await?;
+    let sent: HashSet<(Vec<u8>, AlertKind)> =
+        sqlx::query!(r#"SELECT item, kind as "kind: AlertKind" FROM item_alerts"#)
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| (row.item, row.kind))
+            .collect();
+    let current: HashSet<(Vec<u8>, AlertKind)> = alerts
+        .iter()
+        .map(|alert| (alert.item.as_bytes().to_vec(), alert.kind))
+        .collect();
+    let new: Vec<Alert> = alerts
+        .into_iter()
+        .filter(|alert| !sent.contains(&(alert.item.as_bytes().to_vec(), alert.kind)))
+        .collect();
+
+    let mut delivered = new.is_empty();
+    for sink in config.sinks.iter().filter(|_| !new.is_empty()) {
+        match send(client, sink, &new).await {
+            Ok(()) => delivered = true,
+            Err(err) => log::error!("Failed sending {} alerts: {}", new.len(), err),
+        }
+    }
+
+    let mut tx = db.begin().await?;
+    for (item, kind) in sent.difference(&current) {
+        sqlx::query!(
+            "DELETE FROM item_alerts WHERE item = ? AND kind = ?",
+            item,
+            kind
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    // retried next time if no sink could be reached
+    if delivered {
+        let now = Utc::now();
+        for alert in &new {
+            sqlx::query!(
+                "INSERT OR REPLACE INTO item_alerts (item, kind, sent) VALUES (?, ?, ?)",
+                alert.item,
+                alert.kind,
+                now
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(if delivered { new.len() } else { 0 })
+}
+
+/// Gives up on unreachable sinks instead of holding up the following checks.
+fn http_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(StdDuration::from_secs(10))
+        .timeout(StdDuration::from_secs(30))
+        .build()
+}
+
+pub async fn watch_stock(db: MetadataDatabase, config: Arc<Config>) {
+    let Some(notifications) = &config.notifications else {
+        return;
+    };
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Failed setting up the notification client: {}", err);
+            return;
+        }
+    };
+    let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(
+        notifications.check_interval_mins.saturating_mul(60),
+    ));
+    loop {
+        interval.tick().await;
+        match check(&client, &db, notifications).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Sent {} stock alerts.", count),
+            Err(err) => log::error!("Failed checking stock: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        str::FromStr,
+        sync::{mpsc, Mutex},
+        thread,
+    };
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use super::*;
+
+    /// Request received by the stand-in HTTP server: lowercase header names and values, and the
+    /// body.
+    type Received = Mutex<Vec<(HashMap<String, String>, String)>>;
+
+    async fn receive(
+        received: web::Data<Received>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> HttpResponse {
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_owned(),
+                    value.to_str().unwrap_or_default().to_owned(),
+                )
+            })
+            .collect();
+        received
+            .lock()
+            .unwrap()
+            .push((headers, String::from_utf8_lossy(&body).into_owned()));
+        HttpResponse::Ok().finish()
+    }
+
+    /// Stands in for webhook receivers and ntfy, recording every request.
+    fn start_http_server() -> (String, web::Data<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/homebox", listener.local_addr().unwrap());
+        let received = web::Data::new(Received::default());
+        let data = received.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::to(receive))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        (url, received)
+    }
+
+    /// Stands in for an SMTP server accepting a single connection, and passes on the message.
+    fn start_smtp_server() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.to_ascii_uppercase();
+                if command.starts_with("DATA") {
+                    stream.write_all(b"354 Go ahead\r\n").unwrap();
+                    let mut message = String::new();
+                    loop {
+                        let mut data = String::new();
+                        reader.read_line(&mut data).unwrap();
+                        if data == ".\r\n" {
+                            break;
+                        }
+                        message.push_str(&data);
+                    }
+                    sender.send(message).unwrap();
+                    stream.write_all(b"250 Queued\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    stream.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    stream.write_all(b"250 localhost\r\n").unwrap();
+                }
+                line.clear();
+            }
+        });
+        (port, receiver)
+    }
+
+    fn low_stock() -> Alert {
+        Alert {
+            item: Uuid::new_v4(),
+            name: "Batteries".to_owned(),
+            kind: AlertKind::LowStock,
+            quantity: 1,
+            min_quantity: Some(4),
+            expires_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn sends_email() {
+        let (port, messages) = start_smtp_server();
+        let email = Email {
+            host: "127.0.0.1".to_owned(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "homebox@example.com".to_owned(),
+            to: vec!["me@example.com".to_owned()],
+        };
+        send_email(&email, &[low_stock()]).await.unwrap();
+        let message = messages.recv().unwrap();
+        assert!(message.contains("Subject: Homebox: 1 items need attention"));
+        assert!(message.contains("To: me@example.com"));
+        assert!(message.contains("Batteries: only 1 left, at least 4 wanted"));
+    }
+
+    #[actix_web::test]
+    async fn posts_to_webhooks() {
+        let (url, received) = start_http_server();
+        let webhook = Webhook {
+            url,
+            headers: HashMap::from([("Authorization".to_owned(), "Bearer secret".to_owned())]),
+        };
+        let alert = low_stock();
+        send_webhook(&http_client().unwrap(), &webhook, &[alert.clone()])
+            .await
+            .unwrap();
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        assert_eq!(headers["authorization"], "Bearer secret");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["alerts"][0]["item"], alert.item.to_string());
+        assert_eq!(body["alerts"][0]["kind"], "low_stock");
+        assert_eq!(body["alerts"][0]["min_quantity"], 4);
+    }
+
+    #[actix_web::test]
+    async fn pushes_to_ntfy() {
+        let (url, received) = start_http_server();
+        let ntfy = Ntfy {
+            url,
+            token: Some("token".to_owned()),
+            priority: Some(4),
+        };
+        send_ntfy(&http_client().unwrap(), &ntfy, &[low_stock()])
+            .await
+            .unwrap();
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        assert_eq!(headers["title"], "1 items need attention");
+        assert_eq!(headers["priority"], "4");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(body, "Batteries: only 1 left, at least 4 wanted");
+    }
+
+    async fn database_with_item(quantity: i64, min_quantity: i64) -> MetadataDatabase {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::from_str("sqlite::memory:")
+                    .unwrap()
+                    .foreign_keys(true),
+            )
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+        let container = Uuid::new_v4();
+        let now = Utc::now();
+        sqlx::query("INSERT INTO containers (uuid, created, updated) VALUES (?, ?, ?)")
+            .bind(container)
+            .bind(now)
+            .bind(now)
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO items (uuid, created, updated, name, quantity, min_quantity, container) VALUES (?, ?, ?, 'Batteries', ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(now)
+        .bind(now)
+        .bind(quantity)
+        .bind(min_quantity)
+        .bind(container)
+        .execute(&db)
+        .await
+        .unwrap();
+        db
+    }
+
+    fn webhook_notifications(url: String) -> Notifications {
+        Notifications {
+            check_interval_mins: 60,
+            expiry_warning_days: 7,
+            sinks: vec![Sink::Webhook(Webhook {
+                url,
+                headers: HashMap::new(),
+            })],
+        }
+    }
+
+    #[actix_web::test]
+    async fn sends_each_alert_once_while_it_applies() {
+        let (url, received) = start_http_server();
+        let config = webhook_notifications(url);
+        let client = http_client().unwrap();
+        let db = database_with_item(1, 4).await;
+
+        assert_eq!(check(&client, &db, &config).await.unwrap(), 1);
+        assert_eq!(check(&client, &db, &config).await.unwrap(), 0);
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // restocked, and running low again later on
+        sqlx::query("UPDATE items SET quantity = 5")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(check(&client, &db, &config).await.unwrap(), 0);
+        sqlx::query("UPDATE items SET quantity = 2")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(check(&client, &db, &config).await.unwrap(), 1);
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn retries_alerts_no_sink_received() {
+        // nothing is listening there anymore
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/homebox", listener.local_addr().unwrap())
+        };
+        let config = webhook_notifications(url);
+        let client = http_client().unwrap();
+        let db = database_with_item(1, 4).await;
+
+        assert_eq!(check(&client, &db, &config).await.unwrap(), 0);
+        let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM item_alerts")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+    }
+}
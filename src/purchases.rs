@@ -0,0 +1,143 @@
+//! Purchase details of items, for insurance and warranty claims.
+
+use anyhow::Error;
+use async_graphql::{InputObject, SimpleObject};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{listing::ItemFilter, MetadataDatabase};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
+pub struct Money {
+    pub amount: f64,
+    /// ISO 4217 code
+    pub currency: String,
+}
+
+impl Money {
+    /// Combines the `price` and `currency` columns, which are only ever set together.
+    pub fn from_columns(price: Option<i64>, currency: Option<String>) -> Option<Self> {
+        Some(Money {
+            amount: price? as f64 / 100.0,
+            currency: currency?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+pub struct Purchase {
+    pub purchased_on: Option<NaiveDate>,
+    pub vendor: Option<String>,
+    /// Price of a single piece
+    pub price: Option<Money>,
+    pub serial_number: Option<String>,
+    pub model_number: Option<String>,
+    pub warranty_until: Option<NaiveDate>,
+}
+
+/// Purchase details of an item. Everything left out is cleared.
+#[derive(Debug, Default, InputObject)]
+pub struct PurchaseInput {
+    pub purchased_on: Option<NaiveDate>,
+    pub vendor: Option<String>,
+    /// Price of a single piece
+    pub price: Option<f64>,
+    /// ISO 4217 code, required along with the price
+    pub currency: Option<String>,
+    pub serial_number: Option<String>,
+    pub model_number: Option<String>,
+    pub warranty_until: Option<NaiveDate>,
+}
+
+impl PurchaseInput {
+    /// The price in hundredths and the currency code, as they're stored.
+    fn price(&self) -> Result<(Option<i64>, Option<String>), Error> {
+        match (self.price, &self.currency) {
+            (None, None) => Ok((None, None)),
+            (Some(price), Some(currency)) => {
+                anyhow::ensure!(
+                    price.is_finite() && price >= 0.0,
+                    "Prices can't be negative"
+                );
+                anyhow::ensure!(
+                    currency.len() == 3 && currency.chars().all(|c| c.is_ascii_alphabetic()),
+                    "Currencies have to be given as ISO 4217 code"
+                );
+                Ok((
+                    Some((price * 100.0).round() as i64),
+                    Some(currency.to_ascii_uppercase()),
+                ))
+            }
+            _ => anyhow::bail!("Prices need a currency, and currencies a price"),
+        }
+    }
+}
+
+/// Replaces the purchase details of an item, unless it is in the trash.
+pub async fn store(
+    db: &mut SqliteConnection,
+    item: Uuid,
+    purchase: &PurchaseInput,
+) -> Result<bool, Error> {
+    let (price, currency) = purchase.price()?;
+    let result = sqlx::query!(
+        "UPDATE items SET purchased_on = ?, vendor = ?, price = ?, currency = ?, serial_number = ?, model_number = ?, warranty_until = ? WHERE uuid = ? AND deleted_at IS NULL",
+        purchase.purchased_on,
+        purchase.vendor,
+        price,
+        currency,
+        purchase.serial_number,
+        purchase.model_number,
+        purchase.warranty_until,
+        item
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Summed up purchase prices of items in one currency
+#[derive(Debug, Clone, SimpleObject)]
+pub struct InventoryValue {
+    pub currency: String,
+    /// Prices multiplied by the quantity
+    pub amount: f64,
+    /// Number of items with a price in this currency
+    pub items: usize,
+}
+
+/// Value of all items with a price, by currency. All given restrictions have to apply.
+pub async fn inventory_value(
+    db: &MetadataDatabase,
+    location: Option<Uuid>,
+    container: Option<Uuid>,
+    tag: Option<Uuid>,
+) -> Result<Vec<InventoryValue>, Error> {
+    let mut query = QueryBuilder::new(
+        "SELECT currency, SUM(price * quantity), COUNT(*) FROM items WHERE deleted_at IS NULL AND price IS NOT NULL",
+    );
+    ItemFilter {
+        location,
+        tags: tag.into_iter().collect(),
+        ..Default::default()
+    }
+    .push(&mut query);
+    if let Some(container) = container {
+        query
+            .push(" AND container IN (WITH RECURSIVE subtree(uuid) AS (SELECT ")
+            .push_bind(container)
+            .push(" UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid) SELECT uuid FROM subtree)");
+    }
+    query.push(" GROUP BY currency ORDER BY currency");
+    let rows: Vec<(String, i64, i64)> = query.build_query_as().fetch_all(db).await?;
+    Ok(rows
+        .into_iter()
+        .map(|(currency, amount, items)| InventoryValue {
+            currency,
+            amount: amount as f64 / 100.0,
+            items: items as _,
+        })
+        .collect())
+}
@@ -1,370 +1,2447 @@
-use std::{collections::HashMap, ops::DerefMut};
+use std::sync::Arc;
 
 use anyhow::Error;
 use async_graphql::{
-    futures_util::{lock::MutexGuard, TryStreamExt},
-    Context, EmptySubscription, Object, Schema, SimpleObject,
+    connection,
+    dataloader::DataLoader,
+    futures_util::{future, FutureExt, Stream, StreamExt, TryStreamExt},
+    ComplexObject, Context, Enum, GuardExt, MaybeUndefined, Object, Schema, SimpleObject,
+    Subscription,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, QueryBuilder};
 use uuid::Uuid;
 
-use crate::MetadataDatabase;
+use crate::{
+    api_tokens::{self, ApiToken},
+    changes::{Broker, Change, ChangeKind, ContainerChanged, ItemChanged, LocationChanged},
+    config::Config,
+    custom_fields::{self, Category, FieldInput, FieldKind, FieldValue},
+    gallery::Image,
+    history::{self, Entity, Event, EventRow, Tracker},
+    listing::{
+        ContainerFilter, ContainerSort, CountedConnection, ItemFilter, ItemSort, LocationFilter,
+        Order, Page,
+    },
+    loaders::{
+        CategoryLoader, ContainerChildrenLoader, ContainerImagesLoader, ContainerLoader,
+        ContainerTagsLoader, ItemFieldsLoader, ItemImagesLoader, ItemLoader, ItemTagsLoader,
+        LocationChildrenLoader, LocationLoader,
+    },
+    purchases::{self, InventoryValue, Money, Purchase, PurchaseInput},
+    tags::{self, Tag, Tagged},
+    trash::{self, TrashEntry},
+    user_session::{self, Credential, SessionData, SessionGuard},
+    users::{self, Role, RoleGuard, User},
+    FileDatabase, MetadataDatabase,
+};
 
-pub type HomeboxSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+pub type HomeboxSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
+/// Single container images from before galleries, converted at startup
 pub const CONTAINER_IMAGE_TYPE: u8 = 2;
+pub const LOCATION_IMAGE_TYPE: u8 = 3;
+/// Single item images from before galleries, converted at startup
 pub const ITEM_IMAGE_TYPE: u8 = 11;
+pub const IMAGE_TYPE: u8 = 12;
+/// Resized images, keyed by the key of the original
+pub const VARIANT_TYPE: u8 = 13;
+/// Content hashes and modification times of blobs, keyed by the key of the blob
+pub const BLOB_INFO_TYPE: u8 = 14;
+pub const LOGIN_ATTEMPT_TYPE: u8 = 253;
+pub const API_TOKEN_TYPE: u8 = 254;
 pub const SESSION_TYPE: u8 = 255;
 
 pub struct QueryRoot;
 
+/// Columns of the `locations` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct LocationRow {
+    uuid: Vec<u8>,
+    name: String,
+    description: Option<String>,
+    parent: Option<Vec<u8>>,
+}
+
+impl From<LocationRow> for Location {
+    fn from(row: LocationRow) -> Self {
+        Location {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            name: row.name,
+            description: row.description,
+            parent_id: row.parent.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+        }
+    }
+}
+
+/// Columns of the `items` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct ItemRow {
+    uuid: Vec<u8>,
+    created: NaiveDateTime,
+    updated: NaiveDateTime,
+    name: String,
+    description: Option<String>,
+    quantity: i64,
+    container: Vec<u8>,
+    category: Option<Vec<u8>>,
+    purchased_on: Option<NaiveDate>,
+    vendor: Option<String>,
+    price: Option<i64>,
+    currency: Option<String>,
+    serial_number: Option<String>,
+    model_number: Option<String>,
+    warranty_until: Option<NaiveDate>,
+    min_quantity: Option<i64>,
+    expires_at: Option<NaiveDate>,
+}
+
+impl From<ItemRow> for Item {
+    fn from(row: ItemRow) -> Self {
+        Item {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            created: DateTime::from_utc(row.created, Utc),
+            updated: DateTime::from_utc(row.updated, Utc),
+            name: row.name,
+            quantity: row.quantity as _,
+            min_quantity: row.min_quantity.map(|min_quantity| min_quantity as _),
+            expires_at: row.expires_at,
+            description: row.description,
+            container_id: Uuid::from_slice(&row.container).unwrap(),
+            category_id: row.category.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+            purchase: Purchase {
+                purchased_on: row.purchased_on,
+                vendor: row.vendor,
+                price: Money::from_columns(row.price, row.currency),
+                serial_number: row.serial_number,
+                model_number: row.model_number,
+                warranty_until: row.warranty_until,
+            },
+        }
+    }
+}
+
+/// Columns of the `containers` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct ContainerRow {
+    uuid: Vec<u8>,
+    created: NaiveDateTime,
+    updated: NaiveDateTime,
+    name: Option<String>,
+    location: Option<Vec<u8>>,
+    parent: Option<Vec<u8>>,
+}
+
+impl From<ContainerRow> for Container {
+    fn from(row: ContainerRow) -> Self {
+        Container {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            created: DateTime::from_utc(row.created, Utc),
+            updated: DateTime::from_utc(row.updated, Utc),
+            name: row.name,
+            location_id: row.location.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+            parent_id: row.parent.and_then(|uuid| Uuid::from_slice(&uuid).ok()),
+        }
+    }
+}
+
 impl QueryRoot {
-    async fn fetch_container(
-        mut db: MutexGuard<'_, sqlx::SqliteConnection>,
+    async fn fetch_location(
+        db: &mut sqlx::SqliteConnection,
         id: Uuid,
-    ) -> Result<Option<Container>, Error> {
-        match sqlx::query!("SELECT * FROM containers WHERE uuid = ?", id)
-            .fetch_one(db.deref_mut())
-            .await
-        {
-            Ok(row) => {
-                let location = if let Some(location) = row.location {
-                    sqlx::query!("SELECT * FROM locations WHERE uuid = ?", location)
-                        .fetch_one(db.deref_mut())
-                        .await
-                        .ok()
-                } else {
-                    None
-                };
-                Ok(Some(Container {
-                    id: Uuid::from_slice(&row.uuid).unwrap(),
-                    created: DateTime::from_utc(row.created, Utc),
-                    updated: DateTime::from_utc(row.updated, Utc),
-                    name: row.name,
-                    location: location.map(|location| Location {
-                        id: Uuid::from_slice(&location.uuid).unwrap(),
-                        name: location.name,
-                    }),
-                }))
-            }
-            Err(sqlx::Error::RowNotFound) => Ok(None),
-            Err(err) => Err(err.into()),
-        }
+    ) -> Result<Option<Location>, Error> {
+        Ok(sqlx::query_as!(
+            LocationRow,
+            "SELECT uuid, name, description, parent FROM locations WHERE uuid = ? AND deleted_at IS NULL",
+            id
+        )
+        .fetch_optional(db)
+        .await?
+        .map(Location::from))
     }
 }
 
 #[Object]
 impl QueryRoot {
-    async fn all_locations(&self, ctx: &Context<'_>) -> Result<Vec<Location>, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let mut locations = sqlx::query!("SELECT * from locations").fetch(db.deref_mut());
-        let mut result = Vec::new();
-        while let Some(row) = locations.try_next().await? {
-            result.push(Location {
-                id: Uuid::from_slice(&row.uuid).unwrap(),
-                name: row.name,
-            });
+    #[allow(clippy::too_many_arguments)]
+    async fn all_locations(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: LocationFilter,
+        #[graphql(default)] descending: bool,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<CountedConnection<Location>> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        connection::query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                // one snapshot for the count and the page
+                let mut tx = db.begin().await?;
+                let mut count =
+                    QueryBuilder::new("SELECT COUNT(*) FROM locations WHERE deleted_at IS NULL");
+                filter.push(&mut count);
+                let (total,): (i64,) = count.build_query_as().fetch_one(&mut tx).await?;
+
+                let order = Order::new("name COLLATE NOCASE", descending);
+                let page = Page::new(order, after, before, first, last);
+                let mut query = QueryBuilder::new("SELECT uuid, name, description, parent");
+                page.push_columns(&mut query);
+                query.push(" FROM locations WHERE deleted_at IS NULL");
+                filter.push(&mut query);
+                page.push_range(&mut query)?;
+                let rows = query.build().fetch_all(&mut tx).await?;
+                tx.commit().await?;
+                page.connection(total as _, rows, |row| {
+                    LocationRow::from_row(row).map(Location::from)
+                })
+            },
+        )
+        .await
+    }
+    /// Top-level locations, their sublocations are available through `children`
+    async fn location_tree(&self, ctx: &Context<'_>) -> Result<Vec<Location>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            LocationRow,
+            "SELECT uuid, name, description, parent FROM locations WHERE parent IS NULL AND deleted_at IS NULL"
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Location::from).collect())
+    }
+    async fn location(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a location")] id: Uuid,
+    ) -> Result<Option<Location>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<LocationLoader>>()
+            .load_one(id)
+            .await?)
+    }
+    /// Containers at a location or any of its sublocations
+    async fn containers_in_location(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a location")] id: Uuid,
+    ) -> Result<Vec<Container>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            ContainerRow,
+            r#"WITH RECURSIVE descendants(uuid) AS (
+                SELECT ?
+                UNION SELECT l.uuid FROM locations l JOIN descendants d ON l.parent = d.uuid
+            )
+            SELECT uuid, created, updated, name, location, parent FROM containers
+            WHERE location IN descendants AND deleted_at IS NULL"#,
+            id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Container::from).collect())
+    }
+    /// Items at a location or any of its sublocations
+    async fn items_in_location(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a location")] id: Uuid,
+    ) -> Result<Vec<Item>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            ItemRow,
+            r#"WITH RECURSIVE descendants(uuid) AS (
+                SELECT ?
+                UNION SELECT l.uuid FROM locations l JOIN descendants d ON l.parent = d.uuid
+            )
+            SELECT i.uuid, i.created, i.updated, i.name, i.description, i.quantity, i.container, i.category,
+                i.purchased_on, i.vendor, i.price, i.currency, i.serial_number, i.model_number, i.warranty_until,
+                i.min_quantity, i.expires_at
+            FROM items i JOIN containers c ON i.container = c.uuid
+            WHERE c.location IN descendants AND i.deleted_at IS NULL"#,
+            id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Item::from).collect())
+    }
+    #[allow(clippy::too_many_arguments)]
+    async fn all_containers(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: ContainerFilter,
+        #[graphql(default)] sort: ContainerSort,
+        #[graphql(default)] descending: bool,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<CountedConnection<Container>> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        connection::query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                let mut tx = db.begin().await?;
+                let mut count =
+                    QueryBuilder::new("SELECT COUNT(*) FROM containers WHERE deleted_at IS NULL");
+                filter.push(&mut count);
+                let (total,): (i64,) = count.build_query_as().fetch_one(&mut tx).await?;
+
+                let page = Page::new(sort.order(descending), after, before, first, last);
+                let mut query =
+                    QueryBuilder::new("SELECT uuid, created, updated, name, location, parent");
+                page.push_columns(&mut query);
+                query.push(" FROM containers WHERE deleted_at IS NULL");
+                filter.push(&mut query);
+                page.push_range(&mut query)?;
+                let rows = query.build().fetch_all(&mut tx).await?;
+                tx.commit().await?;
+                page.connection(total as _, rows, |row| {
+                    ContainerRow::from_row(row).map(Container::from)
+                })
+            },
+        )
+        .await
+    }
+    async fn container(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a container")] id: Uuid,
+    ) -> Result<Option<Container>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ContainerLoader>>()
+            .load_one(id)
+            .await?)
+    }
+    async fn items_in_container(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a container")] id: Uuid,
+        #[graphql(desc = "Include items in nested containers", default)] recursive: bool,
+    ) -> Result<Vec<Item>, Error> {
+        if self.container(ctx, id).await?.is_none() {
+            return Err(sqlx::Error::RowNotFound.into());
         }
-        Ok(result)
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            ItemRow,
+            r#"WITH RECURSIVE descendants(uuid) AS (
+                SELECT ?1
+                UNION SELECT c.uuid FROM containers c JOIN descendants d ON c.parent = d.uuid WHERE ?2
+            )
+            SELECT uuid, created, updated, name, description, quantity, container, category,
+                purchased_on, vendor, price, currency, serial_number, model_number, warranty_until,
+                min_quantity, expires_at
+            FROM items WHERE container IN descendants AND deleted_at IS NULL"#,
+            id,
+            recursive
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Item::from).collect())
     }
-    async fn all_containers(&self, ctx: &Context<'_>) -> Result<Vec<Container>, Error> {
-        let locations: HashMap<_, _> = self
-            .all_locations(ctx)
-            .await?
-            .into_iter()
-            .map(|location| (location.id, location))
+    #[allow(clippy::too_many_arguments)]
+    async fn all_items(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: ItemFilter,
+        #[graphql(default)] sort: ItemSort,
+        #[graphql(default)] descending: bool,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<CountedConnection<Item>> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        connection::query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                let mut tx = db.begin().await?;
+                let mut count = QueryBuilder::new("SELECT COUNT(*) FROM items WHERE deleted_at IS NULL");
+                filter.push(&mut count);
+                let (total,): (i64,) = count.build_query_as().fetch_one(&mut tx).await?;
+
+                let page = Page::new(sort.order(descending), after, before, first, last);
+                let mut query = QueryBuilder::new(
+                    "SELECT uuid, created, updated, name, description, quantity, container, category, purchased_on, vendor, price, currency, serial_number, model_number, warranty_until, min_quantity, expires_at",
+                );
+                page.push_columns(&mut query);
+                query.push(" FROM items WHERE deleted_at IS NULL");
+                filter.push(&mut query);
+                page.push_range(&mut query)?;
+                let rows = query.build().fetch_all(&mut tx).await?;
+                tx.commit().await?;
+                page.connection(total as _, rows, |row| ItemRow::from_row(row).map(Item::from))
+            },
+        )
+        .await
+    }
+    async fn item(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of an item")] id: Uuid,
+    ) -> Result<Option<Item>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ItemLoader>>()
+            .load_one(id)
+            .await?)
+    }
+    /// Summed up purchase prices by currency, optionally only of some items
+    async fn inventory_value(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Only items at this location or any of its sublocations")]
+        location: Option<Uuid>,
+        #[graphql(desc = "Only items in this container or any nested one")] container: Option<Uuid>,
+        #[graphql(desc = "Only items with this tag")] tag: Option<Uuid>,
+    ) -> Result<Vec<InventoryValue>, Error> {
+        purchases::inventory_value(
+            ctx.data_unchecked::<MetadataDatabase>(),
+            location,
+            container,
+            tag,
+        )
+        .await
+    }
+    /// Items whose warranty ends within the given number of days, soonest first
+    async fn warranties_expiring(
+        &self,
+        ctx: &Context<'_>,
+        within: u32,
+    ) -> Result<Vec<Item>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let today = Utc::today().naive_utc();
+        let until = today + Duration::days(within.into());
+        let rows = sqlx::query_as!(
+            ItemRow,
+            r#"SELECT uuid, created, updated, name, description, quantity, container, category,
+                purchased_on, vendor, price, currency, serial_number, model_number, warranty_until,
+                min_quantity, expires_at
+            FROM items WHERE warranty_until BETWEEN ? AND ? AND deleted_at IS NULL
+            ORDER BY warranty_until"#,
+            today,
+            until
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Item::from).collect())
+    }
+
+    /// Searches names and descriptions of items, containers and locations, best matches first.
+    /// Every word of the query has to match, the last one also as a prefix.
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        #[graphql(default = 20)] limit: u32,
+        #[graphql(default)] offset: u32,
+    ) -> Result<Vec<SearchHit>, Error> {
+        let mut terms: Vec<_> = query
+            .split_whitespace()
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
             .collect();
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let mut containers = sqlx::query!("SELECT * FROM containers").fetch(db.deref_mut());
-        // let mut containers = sqlx::query!("SELECT c.uuid as uuid, c.created as created, c.updated as updated, c.name as name, l.uuid as location_uuid, l.name as location_name FROM containers as c LEFT JOIN locations as l ON (c.location = l.uuid)").fetch(&mut db);
+        match terms.last_mut() {
+            Some(last) => last.push('*'),
+            None => return Ok(Vec::new()),
+        }
+        let query = terms.join(" ");
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query!(
+            r#"SELECT kind as "kind!: SearchKind", uuid as "uuid!: Vec<u8>",
+                highlight(search_index, 2, char(2), char(3)) as "name: String",
+                snippet(search_index, 3, char(2), char(3), '…', 16) as "description: String",
+                bm25(search_index, 0.0, 0.0, 10.0, 1.0) as "rank!: f64"
+            FROM search_index WHERE search_index MATCH ?
+            ORDER BY rank LIMIT ? OFFSET ?"#,
+            query,
+            limit,
+            offset
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchHit {
+                kind: row.kind,
+                id: Uuid::from_slice(&row.uuid).unwrap(),
+                highlighted_name: highlight_html(&row.name.unwrap_or_default()),
+                highlighted_description: row
+                    .description
+                    .filter(|description| !description.is_empty())
+                    .map(|description| highlight_html(&description)),
+                rank: -row.rank,
+            })
+            .collect())
+    }
+
+    /// The currently logged in user
+    async fn me(&self, ctx: &Context<'_>) -> User {
+        ctx.data_unchecked::<User>().clone()
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<User>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let mut users = sqlx::query!(
+            r#"SELECT uuid, created, updated, username, disabled, role as "role: Role" FROM users"#
+        )
+        .fetch(db);
         let mut result = Vec::new();
-        while let Some(row) = containers.try_next().await? {
-            result.push(Container {
+        while let Some(row) = users.try_next().await? {
+            result.push(User {
                 id: Uuid::from_slice(&row.uuid).unwrap(),
                 created: DateTime::from_utc(row.created, Utc),
                 updated: DateTime::from_utc(row.updated, Utc),
-                name: row.name,
-                location: row
-                    .location
-                    .and_then(|uuid| Uuid::from_slice(&uuid).ok())
-                    .and_then(|uuid| locations.get(&uuid).cloned()),
+                username: row.username,
+                disabled: row.disabled,
+                role: row.role,
             });
         }
         Ok(result)
     }
-    async fn container(
+    /// Active sessions of the currently logged in user. Admins can query other users' sessions.
+    async fn sessions(
+        &self,
+        ctx: &Context<'_>,
+        user: Option<Uuid>,
+    ) -> Result<Vec<SessionData>, Error> {
+        let current = ctx.data_unchecked::<User>();
+        let user = user.unwrap_or(current.id);
+        anyhow::ensure!(
+            user == current.id || current.role >= Role::Admin,
+            "Forbidden"
+        );
+        Ok(user_session::user_sessions(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            user,
+        ))
+    }
+    /// Changes to the inventory, newest first
+    #[allow(clippy::too_many_arguments)]
+    async fn recent_changes(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Only changes to this kind of object")] entity: Option<Entity>,
+        #[graphql(desc = "Only changes made by this user")] user: Option<Uuid>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<CountedConnection<Event>> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let push_filter = move |query: &mut QueryBuilder<'_, sqlx::Sqlite>| {
+            if let Some(entity) = entity {
+                query.push(" AND e.entity = ").push_bind(entity);
+            }
+            if let Some(user) = user {
+                query.push(" AND e.user = ").push_bind(user);
+            }
+        };
+        connection::query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                let mut tx = db.begin().await?;
+                let mut count = QueryBuilder::new("SELECT COUNT(*) FROM events e WHERE 1");
+                push_filter(&mut count);
+                let (total,): (i64,) = count.build_query_as().fetch_one(&mut tx).await?;
+
+                let page = Page::new(Order::unique("e.id", true), after, before, first, last);
+                let mut query = QueryBuilder::new(history::EVENT_COLUMNS);
+                page.push_columns(&mut query);
+                query.push(history::EVENT_TABLES).push(" WHERE 1");
+                push_filter(&mut query);
+                page.push_range(&mut query)?;
+                let rows = query.build().fetch_all(&mut tx).await?;
+                tx.commit().await?;
+                page.connection(total as _, rows, |row| {
+                    EventRow::from_row(row).map(Event::from)
+                })
+            },
+        )
+        .await
+    }
+    /// All categories with their custom fields, sorted by name
+    async fn categories(&self, ctx: &Context<'_>) -> Result<Vec<Category>, Error> {
+        custom_fields::categories(ctx.data_unchecked::<MetadataDatabase>()).await
+    }
+    async fn category(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a category")] id: Uuid,
+    ) -> Result<Option<Category>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<CategoryLoader>>()
+            .load_one(id)
+            .await?)
+    }
+    /// All tags, sorted by name
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<Tag>, Error> {
+        tags::list(ctx.data_unchecked::<MetadataDatabase>()).await
+    }
+    /// Deleted items, containers and locations that can still be restored
+    async fn trash(&self, ctx: &Context<'_>) -> Result<Vec<TrashEntry>, Error> {
+        trash::list(
+            ctx.data_unchecked::<MetadataDatabase>(),
+            ctx.data_unchecked::<Arc<Config>>(),
+        )
+        .await
+    }
+    /// Personal access tokens of the currently logged in user
+    async fn api_tokens(&self, ctx: &Context<'_>) -> Vec<ApiToken> {
+        api_tokens::list(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            ctx.data_unchecked::<User>().id,
+        )
+    }
+}
+
+pub struct MutationRoot;
+
+impl MutationRoot {
+    /// Nested containers are always at the location of their outer container. Returns the
+    /// containers that were moved along.
+    async fn propagate_location(
+        db: &mut sqlx::SqliteConnection,
+        ctx: &Context<'_>,
+        id: Uuid,
+    ) -> Result<Vec<Uuid>, Error> {
+        let descendants = sqlx::query_scalar!(
+            r#"WITH RECURSIVE descendants(uuid) AS (
+                SELECT uuid FROM containers WHERE parent = ?1
+                UNION SELECT c.uuid FROM containers c JOIN descendants d ON c.parent = d.uuid
+            )
+            SELECT uuid as "uuid!: Vec<u8>" FROM descendants"#,
+            id
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        if descendants.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tracker = Tracker::new(db, Entity::Container, &uuids(descendants)?).await?;
+        let now = Utc::now();
+        sqlx::query!(
+            r#"WITH RECURSIVE descendants(uuid) AS (
+                SELECT uuid FROM containers WHERE parent = ?1
+                UNION SELECT c.uuid FROM containers c JOIN descendants d ON c.parent = d.uuid
+            )
+            UPDATE containers SET updated = ?2, location = (SELECT location FROM containers WHERE uuid = ?1)
+            WHERE uuid IN descendants AND location IS NOT (SELECT location FROM containers WHERE uuid = ?1)"#,
+            id,
+            now
+        )
+        .execute(&mut *db)
+        .await?;
+        tracker.record(db, ctx, ChangeKind::Moved).await
+    }
+
+    /// Tags are shown with their items and containers, so those count as updated.
+    async fn tags_changed(ctx: &Context<'_>, items: &[Uuid], containers: &[Uuid]) {
+        let broker = ctx.data_unchecked::<Arc<Broker>>();
+        broker.items(ChangeKind::Updated, items).await;
+        broker.containers(ChangeKind::Updated, containers).await;
+    }
+
+    /// Snapshots of tagged items and containers, before their tags are changed.
+    async fn track_tagged(
+        db: &mut sqlx::SqliteConnection,
+        items: &[Uuid],
+        containers: &[Uuid],
+    ) -> Result<(Tracker, Tracker), Error> {
+        Ok((
+            Tracker::new(db, Entity::Item, items).await?,
+            Tracker::new(db, Entity::Container, containers).await?,
+        ))
+    }
+
+    async fn record_tagged(
+        db: &mut sqlx::SqliteConnection,
+        ctx: &Context<'_>,
+        (items, containers): (Tracker, Tracker),
+    ) -> Result<(), Error> {
+        items.record(db, ctx, ChangeKind::Updated).await?;
+        containers.record(db, ctx, ChangeKind::Updated).await?;
+        Ok(())
+    }
+
+    /// Items with a value of the custom field
+    async fn items_with_field(
+        db: &mut sqlx::SqliteConnection,
+        field: Uuid,
+    ) -> Result<Vec<Uuid>, Error> {
+        uuids(
+            sqlx::query_scalar!(
+                "SELECT v.item FROM item_fields v JOIN items i ON i.uuid = v.item WHERE v.field = ? AND i.deleted_at IS NULL",
+                field
+            )
+            .fetch_all(db)
+            .await?,
+        )
+    }
+
+    /// Attaches and detaches tags of an item or container.
+    async fn retag(
+        ctx: &Context<'_>,
+        tagged: Tagged,
+        id: Uuid,
+        tags: &[Uuid],
+        attach: bool,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let entity = match tagged {
+            Tagged::Item => Entity::Item,
+            Tagged::Container => Entity::Container,
+        };
+        let tracker = Tracker::new(&mut tx, entity, &[id]).await?;
+        let changed = if attach {
+            tags::attach(&mut tx, tagged, id, tags).await?
+        } else {
+            tags::detach(&mut tx, tagged, id, tags).await?
+        };
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        if changed {
+            match tagged {
+                Tagged::Item => Self::tags_changed(ctx, &[id], &[]).await,
+                Tagged::Container => Self::tags_changed(ctx, &[], &[id]).await,
+            }
+        }
+        Ok(changed)
+    }
+
+    async fn restore_item(ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(container_trashed) = sqlx::query_scalar!(
+            r#"SELECT c.deleted_at IS NOT NULL as "trashed!: bool"
+            FROM items i JOIN containers c ON c.uuid = i.container
+            WHERE i.uuid = ? AND i.deleted_at IS NOT NULL"#,
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(false);
+        };
+        anyhow::ensure!(
+            !container_trashed,
+            "The item's container is in the trash, restore that instead"
+        );
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        sqlx::query!("UPDATE items SET deleted_at = NULL WHERE uuid = ?", id)
+            .execute(&mut tx)
+            .await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Restored).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Restored, &[id])
+            .await;
+        Ok(true)
+    }
+
+    /// The location itself unless it's in the trash, or else its nearest ancestor that isn't.
+    pub(crate) async fn live_location(
+        db: &mut sqlx::SqliteConnection,
+        location: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        Ok(sqlx::query_scalar!(
+            r#"WITH RECURSIVE ancestors(uuid, parent, deleted_at) AS (
+                SELECT uuid, parent, deleted_at FROM locations WHERE uuid = ?
+                UNION SELECT l.uuid, l.parent, l.deleted_at FROM locations l
+                JOIN ancestors a ON l.uuid = a.parent WHERE a.deleted_at IS NOT NULL
+            )
+            SELECT uuid as "uuid!: Vec<u8>" FROM ancestors WHERE deleted_at IS NULL"#,
+            location
+        )
+        .fetch_optional(db)
+        .await?)
+    }
+
+    /// Also restores the nested containers and items that were trashed along with it. If its
+    /// location has been trashed since, it's put at the nearest location that wasn't.
+    async fn restore_container(ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(container) = sqlx::query!(
+            r#"SELECT (SELECT deleted_at FROM containers WHERE uuid = c.parent) IS NOT NULL as "parent_trashed!: bool",
+                location
+            FROM containers c WHERE uuid = ? AND deleted_at IS NOT NULL"#,
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(false);
+        };
+        anyhow::ensure!(
+            !container.parent_trashed,
+            "The outer container is in the trash, restore that instead"
+        );
+        let location = Self::live_location(&mut tx, container.location.clone()).await?;
+        let containers = uuids(
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE subtree(uuid) AS (
+                    SELECT ?1
+                    UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                    WHERE c.deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)
+                )
+                SELECT uuid as "uuid!: Vec<u8>" FROM subtree"#,
+                id
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let items = uuids(
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE subtree(uuid) AS (
+                    SELECT ?1
+                    UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                    WHERE c.deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)
+                )
+                SELECT uuid FROM items WHERE container IN subtree
+                AND deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)"#,
+                id
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let container_tracker = Tracker::new(&mut tx, Entity::Container, &containers).await?;
+        let item_tracker = Tracker::new(&mut tx, Entity::Item, &items).await?;
+        if location != container.location {
+            sqlx::query!(
+                "UPDATE containers SET location = ? WHERE uuid = ?",
+                location,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        // items go first, they're looked up by the time stamp of the container
+        sqlx::query!(
+            r#"WITH RECURSIVE subtree(uuid) AS (
+                SELECT ?1
+                UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                WHERE c.deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)
+            )
+            UPDATE items SET deleted_at = NULL WHERE container IN subtree
+            AND deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)"#,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"WITH RECURSIVE subtree(uuid) AS (
+                SELECT ?1
+                UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                WHERE c.deleted_at = (SELECT deleted_at FROM containers WHERE uuid = ?1)
+            )
+            UPDATE containers SET deleted_at = NULL WHERE uuid IN subtree"#,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        container_tracker
+            .record(&mut tx, ctx, ChangeKind::Restored)
+            .await?;
+        item_tracker
+            .record(&mut tx, ctx, ChangeKind::Restored)
+            .await?;
+        tx.commit().await?;
+        let broker = ctx.data_unchecked::<Arc<Broker>>();
+        broker.containers(ChangeKind::Restored, &containers).await;
+        broker.items(ChangeKind::Restored, &items).await;
+        Ok(true)
+    }
+
+    /// If its parent has been trashed since, it's put below the nearest ancestor that wasn't.
+    async fn restore_location(ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(parent) = sqlx::query_scalar!(
+            "SELECT parent FROM locations WHERE uuid = ? AND deleted_at IS NOT NULL",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(false);
+        };
+        let parent = Self::live_location(&mut tx, parent).await?;
+        let tracker = Tracker::new(&mut tx, Entity::Location, &[id]).await?;
+        sqlx::query!(
+            "UPDATE locations SET deleted_at = NULL, parent = ? WHERE uuid = ?",
+            parent,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Restored).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .locations(ChangeKind::Restored, &[id])
+            .await;
+        Ok(true)
+    }
+}
+
+#[Object]
+impl MutationRoot {
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn add_location(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        description: Option<String>,
+        #[graphql(desc = "Location this one is part of")] parent: Option<Uuid>,
+    ) -> Result<Uuid, Error> {
+        let uuid = Uuid::new_v4();
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        if let Some(parent) = parent {
+            anyhow::ensure!(
+                QueryRoot::fetch_location(&mut tx, parent).await?.is_some(),
+                "Parent location doesn't exist"
+            );
+        }
+        let tracker = Tracker::new(&mut tx, Entity::Location, &[uuid]).await?;
+        sqlx::query!(
+            "INSERT INTO locations (uuid, name, description, parent) VALUES (?, ?, ?, ?)",
+            uuid,
+            name,
+            description,
+            parent
+        )
+        .execute(&mut tx)
+        .await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Created).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .locations(ChangeKind::Created, &[uuid])
+            .await;
+        Ok(uuid)
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn update_location(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        name: String,
+        description: Option<String>,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Location, &[id]).await?;
+        let result = sqlx::query!(
+            "UPDATE locations SET name = ?, description = ? WHERE uuid = ? AND deleted_at IS NULL",
+            name,
+            description,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .locations(ChangeKind::Updated, &[id])
+            .await;
+        Ok(true)
+    }
+    /// Makes a location part of another one, or a top-level location
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn move_location(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a location")] id: Uuid,
+        #[graphql(desc = "New parent location, none for a top-level location")] parent: Option<
+            Uuid,
+        >,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        if let Some(parent) = parent {
+            anyhow::ensure!(
+                QueryRoot::fetch_location(&mut tx, parent).await?.is_some(),
+                "Parent location doesn't exist"
+            );
+            let cycle = sqlx::query_scalar!(
+                r#"WITH RECURSIVE ancestors(uuid) AS (
+                    SELECT ?
+                    UNION SELECT l.parent FROM locations l JOIN ancestors a ON l.uuid = a.uuid
+                    WHERE l.parent IS NOT NULL
+                )
+                SELECT COUNT(*) FROM ancestors WHERE uuid = ?"#,
+                parent,
+                id
+            )
+            .fetch_one(&mut tx)
+            .await?;
+            anyhow::ensure!(cycle == 0, "A location can't be part of itself");
+        }
+        let tracker = Tracker::new(&mut tx, Entity::Location, &[id]).await?;
+        let result = sqlx::query!(
+            "UPDATE locations SET parent = ? WHERE uuid = ? AND deleted_at IS NULL",
+            parent,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Moved).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .locations(ChangeKind::Moved, &[id])
+            .await;
+        Ok(true)
+    }
+    /// Moves a location to the trash. Its sublocations and containers are moved up to its parent.
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn delete_location(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(location) = QueryRoot::fetch_location(&mut tx, id).await? else {
+            return Ok(false);
+        };
+        let sublocations = uuids(
+            sqlx::query_scalar!(
+                "SELECT uuid FROM locations WHERE parent = ? AND deleted_at IS NULL",
+                id
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let containers = uuids(
+            sqlx::query_scalar!(
+                "SELECT uuid FROM containers WHERE location = ? AND deleted_at IS NULL",
+                id
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let locations = Tracker::new(
+            &mut tx,
+            Entity::Location,
+            &[&[id][..], &sublocations].concat(),
+        )
+        .await?;
+        let moved_containers = Tracker::new(&mut tx, Entity::Container, &containers).await?;
+        sqlx::query!(
+            "UPDATE locations SET parent = ? WHERE parent = ?",
+            location.parent_id,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        let now = Utc::now();
+        sqlx::query!(
+            "UPDATE containers SET updated = ?, location = ? WHERE location = ?",
+            now,
+            location.parent_id,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE locations SET deleted_at = ? WHERE uuid = ?",
+            now,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        locations.record(&mut tx, ctx, ChangeKind::Moved).await?;
+        moved_containers
+            .record(&mut tx, ctx, ChangeKind::Moved)
+            .await?;
+        tx.commit().await?;
+        let broker = ctx.data_unchecked::<Arc<Broker>>();
+        broker.locations(ChangeKind::Deleted, &[id]).await;
+        broker.locations(ChangeKind::Moved, &sublocations).await;
+        broker.containers(ChangeKind::Moved, &containers).await;
+        Ok(true)
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn add_container(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Name of the new container")] name: String,
+        #[graphql(desc = "Physical location of container, ignored for nested containers")]
+        location: Option<Uuid>,
+        #[graphql(desc = "Container this one is put into")] parent: Option<Uuid>,
+    ) -> Result<Uuid, Error> {
+        let uuid = Uuid::new_v4();
+        let now = Utc::now();
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Container, &[uuid]).await?;
+        sqlx::query!(
+            "INSERT INTO containers (uuid, created, updated, name, location, parent) VALUES (?, ?, ?, ?, COALESCE((SELECT location FROM containers WHERE uuid = ?), ?), ?)",
+            uuid,
+            now,
+            now,
+            name,
+            parent,
+            location,
+            parent
+        )
+        .execute(&mut tx)
+        .await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Created).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .containers(ChangeKind::Created, &[uuid])
+            .await;
+        Ok(uuid)
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn update_container(
         &self,
         ctx: &Context<'_>,
         #[graphql(desc = "Primary key of a container")] id: Uuid,
-    ) -> Result<Option<Container>, Error> {
-        Self::fetch_container(ctx.data_unchecked::<MetadataDatabase>().lock().await, id).await
+        #[graphql(desc = "New name")] name: String,
+        #[graphql(desc = "New physical location, ignored for nested containers")] location: Uuid,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Container, &[id]).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE containers SET name = ?, location = CASE WHEN parent IS NULL THEN ? ELSE location END, updated = ? WHERE uuid = ? AND deleted_at IS NULL",
+            name,
+            location,
+            now,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        let moved = Self::propagate_location(&mut tx, ctx, id).await?;
+        tx.commit().await?;
+        let broker = ctx.data_unchecked::<Arc<Broker>>();
+        broker.containers(ChangeKind::Updated, &[id]).await;
+        broker.containers(ChangeKind::Moved, &moved).await;
+        Ok(true)
     }
-    async fn items_in_container(
+    /// Puts a container into another one, or takes it out to the given location
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn move_container(
         &self,
         ctx: &Context<'_>,
         #[graphql(desc = "Primary key of a container")] id: Uuid,
-    ) -> Result<Vec<Item>, Error> {
-        if let Some(container) = self.container(ctx, id).await? {
-            let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-            let mut items =
-                sqlx::query!("SELECT * FROM items WHERE container = ?", id).fetch(db.deref_mut());
-            let mut result = Vec::new();
-            while let Some(row) = items.try_next().await? {
-                result.push(Item {
-                    id: Uuid::from_slice(&row.uuid).unwrap(),
-                    created: DateTime::from_utc(row.created, Utc),
-                    updated: DateTime::from_utc(row.updated, Utc),
-                    name: row.name,
-                    quantity: row.quantity as _,
-                    description: row.description,
-                    container: container.clone(),
-                });
-            }
-            Ok(result)
+        #[graphql(desc = "New outer container, none to take it out")] parent: Option<Uuid>,
+        #[graphql(desc = "New physical location when taken out, defaults to the current one")]
+        location: Option<Uuid>,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        if let Some(parent) = parent {
+            let cycle = sqlx::query_scalar!(
+                r#"WITH RECURSIVE ancestors(uuid) AS (
+                    SELECT ?
+                    UNION SELECT c.parent FROM containers c JOIN ancestors a ON c.uuid = a.uuid
+                    WHERE c.parent IS NOT NULL
+                )
+                SELECT COUNT(*) FROM ancestors WHERE uuid = ?"#,
+                parent,
+                id
+            )
+            .fetch_one(&mut tx)
+            .await?;
+            anyhow::ensure!(cycle == 0, "A container can't be put into itself");
+        }
+        let tracker = Tracker::new(&mut tx, Entity::Container, &[id]).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE containers SET updated = ?1, parent = ?2, location = COALESCE((SELECT location FROM containers WHERE uuid = ?2), ?3, location) WHERE uuid = ?4 AND deleted_at IS NULL",
+            now,
+            parent,
+            location,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Moved).await?;
+        let mut moved = Self::propagate_location(&mut tx, ctx, id).await?;
+        tx.commit().await?;
+        moved.insert(0, id);
+        ctx.data_unchecked::<Arc<Broker>>()
+            .containers(ChangeKind::Moved, &moved)
+            .await;
+        Ok(true)
+    }
+    /// Moves a container to the trash. Its items have to be trashed along with `cascade` or moved
+    /// elsewhere with `moveItemsTo`, otherwise only empty containers can be deleted.
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn delete_container(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a container")] id: Uuid,
+        #[graphql(
+            desc = "Also trash all items and nested containers, instead of moving nested containers up a level",
+            default
+        )]
+        cascade: bool,
+        #[graphql(desc = "Container to move the items into")] move_items_to: Option<Uuid>,
+    ) -> Result<bool, Error> {
+        anyhow::ensure!(
+            !cascade || move_items_to.is_none(),
+            "Items can't be both deleted and moved"
+        );
+        anyhow::ensure!(
+            move_items_to != Some(id),
+            "Items can't be moved into the container that is deleted"
+        );
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let deleted = uuids(
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE subtree(uuid) AS (
+                    SELECT uuid FROM containers WHERE uuid = ?1 AND deleted_at IS NULL
+                    UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                    WHERE ?2 AND c.deleted_at IS NULL
+                )
+                SELECT uuid as "uuid!: Vec<u8>" FROM subtree"#,
+                id,
+                cascade
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        if deleted.is_empty() {
+            return Ok(false);
+        }
+        // without cascading, these are moved up a level
+        let nested = if cascade {
+            Vec::new()
+        } else {
+            uuids(
+                sqlx::query_scalar!(
+                    "SELECT uuid FROM containers WHERE parent = ? AND deleted_at IS NULL",
+                    id
+                )
+                .fetch_all(&mut tx)
+                .await?,
+            )?
+        };
+        // either trashed along or moved to `move_items_to`
+        let items = uuids(
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE subtree(uuid) AS (
+                    SELECT ?1
+                    UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                    WHERE ?2 AND c.deleted_at IS NULL
+                )
+                SELECT uuid FROM items WHERE container IN subtree AND deleted_at IS NULL"#,
+                id,
+                cascade
+            )
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let container_tracker = Tracker::new(
+            &mut tx,
+            Entity::Container,
+            &[&deleted[..], &nested].concat(),
+        )
+        .await?;
+        let item_tracker = Tracker::new(&mut tx, Entity::Item, &items).await?;
+
+        let now = Utc::now();
+        if let Some(target) = move_items_to {
+            sqlx::query!(
+                "UPDATE items SET updated = ?, container = ? WHERE container = ? AND deleted_at IS NULL",
+                now,
+                target,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        if cascade {
+            // the same time stamp marks them as trashed together, to restore them together
+            sqlx::query!(
+                r#"WITH RECURSIVE subtree(uuid) AS (
+                    SELECT ?1
+                    UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                    WHERE c.deleted_at IS NULL
+                )
+                UPDATE items SET deleted_at = ?2 WHERE container IN subtree AND deleted_at IS NULL"#,
+                id,
+                now
+            )
+            .execute(&mut tx)
+            .await?;
         } else {
-            Err(sqlx::Error::RowNotFound.into())
+            anyhow::ensure!(
+                move_items_to.is_some() || items.is_empty(),
+                "The container isn't empty, use `cascade` or `moveItemsTo`"
+            );
+            sqlx::query!(
+                "UPDATE containers SET updated = ?, parent = (SELECT parent FROM containers WHERE uuid = ?) WHERE parent = ?",
+                now,
+                id,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
         }
+        sqlx::query!(
+            r#"WITH RECURSIVE subtree(uuid) AS (
+                SELECT ?1
+                UNION SELECT c.uuid FROM containers c JOIN subtree s ON c.parent = s.uuid
+                WHERE ?2 AND c.deleted_at IS NULL
+            )
+            UPDATE containers SET deleted_at = ?3 WHERE uuid IN subtree AND deleted_at IS NULL"#,
+            id,
+            cascade,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+        container_tracker
+            .record(&mut tx, ctx, ChangeKind::Moved)
+            .await?;
+        item_tracker.record(&mut tx, ctx, ChangeKind::Moved).await?;
+        tx.commit().await?;
+
+        let broker = ctx.data_unchecked::<Arc<Broker>>();
+        broker.containers(ChangeKind::Deleted, &deleted).await;
+        broker.containers(ChangeKind::Moved, &nested).await;
+        if move_items_to.is_some() {
+            broker.items(ChangeKind::Moved, &items).await;
+        } else {
+            broker.items(ChangeKind::Deleted, &items).await;
+        }
+        Ok(true)
     }
-    async fn all_items(&self, ctx: &Context<'_>) -> Result<Vec<Item>, Error> {
-        let containers: HashMap<_, _> = self
-            .all_containers(ctx)
-            .await?
-            .into_iter()
-            .map(|container| (container.id, container))
-            .collect();
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let mut items = sqlx::query!("SELECT * FROM items").fetch(db.deref_mut());
-        let mut result = Vec::new();
-        while let Some(row) = items.try_next().await? {
-            if let Some(container) = containers.get(&Uuid::from_slice(&row.container).unwrap()) {
-                result.push(Item {
-                    id: Uuid::from_slice(&row.uuid).unwrap(),
-                    created: DateTime::from_utc(row.created, Utc),
-                    updated: DateTime::from_utc(row.updated, Utc),
-                    name: row.name,
-                    quantity: row.quantity as _,
-                    description: row.description,
-                    container: container.clone(),
-                });
-            }
+
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    #[allow(clippy::too_many_arguments)]
+    async fn add_item(
+        &self,
+        ctx: &Context<'_>,
+        container: Uuid,
+        name: String,
+        quantity: usize,
+        description: Option<String>,
+        #[graphql(desc = "Stock is reported as low below this quantity")] min_quantity: Option<
+            usize,
+        >,
+        expires_at: Option<NaiveDate>,
+        category: Option<Uuid>,
+        #[graphql(desc = "Values of the category's custom fields", default)] fields: Vec<
+            FieldInput,
+        >,
+        purchase: Option<PurchaseInput>,
+    ) -> Result<Uuid, Error> {
+        let uuid = Uuid::new_v4();
+        let now = Utc::now();
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[uuid]).await?;
+        let quantity = quantity as i64;
+        let min_quantity = min_quantity.map(|q| q as i64);
+        sqlx::query!("INSERT INTO items (uuid, created, updated, name, description, quantity, min_quantity, expires_at, container, category) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", uuid, now, now, name, description, quantity, min_quantity, expires_at, container, category).execute(&mut tx).await?;
+        custom_fields::set_values(&mut tx, uuid, category, &fields).await?;
+        if let Some(purchase) = purchase {
+            purchases::store(&mut tx, uuid, &purchase).await?;
         }
-        Ok(result)
+        tracker.record(&mut tx, ctx, ChangeKind::Created).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Created, &[uuid])
+            .await;
+        Ok(uuid)
     }
-    async fn item(
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    #[allow(clippy::too_many_arguments)]
+    async fn update_item(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        name: String,
+        description: Option<String>,
+        quantity: Option<usize>,
+        #[graphql(
+            desc = "Stock is reported as low below this quantity. Kept when left out, null clears it."
+        )]
+        min_quantity: MaybeUndefined<usize>,
+        #[graphql(desc = "Kept when left out, null clears it")] expires_at: MaybeUndefined<
+            NaiveDate,
+        >,
+        #[graphql(desc = "Replaces all purchase details, they're kept when left out")]
+        purchase: Option<PurchaseInput>,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        let quantity = quantity.map(|q| q as i64);
+        let keep_min_quantity = min_quantity.is_undefined();
+        let min_quantity = min_quantity.value().map(|q| *q as i64);
+        let keep_expires_at = expires_at.is_undefined();
+        let expires_at = expires_at.value().copied();
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE items SET updated = ?, name = ?, description = ?, quantity = ?,
+                min_quantity = CASE WHEN ? THEN min_quantity ELSE ? END,
+                expires_at = CASE WHEN ? THEN expires_at ELSE ? END
+            WHERE uuid = ? AND deleted_at IS NULL",
+            now,
+            name,
+            description,
+            quantity,
+            keep_min_quantity,
+            min_quantity,
+            keep_expires_at,
+            expires_at,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        if let Some(purchase) = purchase {
+            purchases::store(&mut tx, id, &purchase).await?;
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &[id])
+            .await;
+        Ok(true)
+    }
+    /// Puts an item into another category. Values of fields that aren't part of it are dropped.
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn set_item_category(
         &self,
         ctx: &Context<'_>,
         #[graphql(desc = "Primary key of an item")] id: Uuid,
-    ) -> Result<Option<Item>, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        match sqlx::query!("SELECT * FROM items WHERE uuid = ?", id)
-            .fetch_one(db.deref_mut())
-            .await
-        {
-            Ok(row) => {
-                if let Some(container) =
-                    Self::fetch_container(db, Uuid::from_slice(&row.container).unwrap()).await?
-                {
-                    Ok(Some(Item {
-                        id: Uuid::from_slice(&row.uuid).unwrap(),
-                        created: DateTime::from_utc(row.created, Utc),
-                        updated: DateTime::from_utc(row.updated, Utc),
-                        name: row.name,
-                        quantity: row.quantity as _,
-                        description: row.description,
-                        container,
-                    }))
-                } else {
-                    Err(sqlx::Error::RowNotFound.into())
-                }
-            }
-            Err(sqlx::Error::RowNotFound) => Ok(None),
-            Err(err) => Err(err.into()),
+        category: Option<Uuid>,
+        #[graphql(desc = "Values of the new category's custom fields", default)] fields: Vec<
+            FieldInput,
+        >,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE items SET updated = ?, category = ? WHERE uuid = ? AND deleted_at IS NULL",
+            now,
+            category,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
         }
+        custom_fields::drop_foreign_values(&mut tx, id).await?;
+        custom_fields::set_values(&mut tx, id, category, &fields).await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &[id])
+            .await;
+        Ok(true)
+    }
+    /// Changes values of an item's custom fields
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn set_item_fields(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of an item")] id: Uuid,
+        fields: Vec<FieldInput>,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(category) = sqlx::query_scalar!(
+            "SELECT category FROM items WHERE uuid = ? AND deleted_at IS NULL",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(false);
+        };
+        let category = category.map(|uuid| Uuid::from_slice(&uuid)).transpose()?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        custom_fields::set_values(&mut tx, id, category, &fields).await?;
+        let now = Utc::now();
+        sqlx::query!("UPDATE items SET updated = ? WHERE uuid = ?", now, id)
+            .execute(&mut tx)
+            .await?;
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &[id])
+            .await;
+        Ok(true)
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn move_item(&self, ctx: &Context<'_>, id: Uuid, container: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE items SET updated = ?, container = ? WHERE uuid = ? AND deleted_at IS NULL",
+            now,
+            container,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Moved).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Moved, &[id])
+            .await;
+        Ok(true)
+    }
+    /// Moves an item to the trash
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn delete_item(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &[id]).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE items SET deleted_at = ? WHERE uuid = ? AND deleted_at IS NULL",
+            now,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tracker.record(&mut tx, ctx, ChangeKind::Deleted).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Deleted, &[id])
+            .await;
+        Ok(true)
     }
-}
-
-pub struct MutationRoot;
 
-#[Object]
-impl MutationRoot {
-    async fn add_location(&self, ctx: &Context<'_>, name: String) -> Result<Uuid, Error> {
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn create_category(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        description: Option<String>,
+    ) -> Result<Uuid, Error> {
         let uuid = Uuid::new_v4();
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
+        let db = ctx.data_unchecked::<MetadataDatabase>();
         sqlx::query!(
-            "INSERT INTO locations (uuid, name) VALUES (?, ?)",
+            "INSERT INTO categories (uuid, name, description) VALUES (?, ?, ?)",
             uuid,
-            name
+            name,
+            description
         )
-        .execute(db.deref_mut())
+        .execute(db)
         .await?;
         Ok(uuid)
     }
-    async fn update_location(
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn update_category(
         &self,
         ctx: &Context<'_>,
         id: Uuid,
         name: String,
+        description: Option<String>,
     ) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let result = sqlx::query!("UPDATE locations SET name = ? WHERE uuid = ?", name, id)
-            .execute(db.deref_mut())
-            .await?;
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let result = sqlx::query!(
+            "UPDATE categories SET name = ?, description = ? WHERE uuid = ?",
+            name,
+            description,
+            id
+        )
+        .execute(db)
+        .await?;
         Ok(result.rows_affected() > 0)
     }
-    async fn delete_location(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let result = sqlx::query!("DELETE FROM locations WHERE uuid = ?", id)
-            .execute(db.deref_mut())
-            .await?;
-        if result.rows_affected() > 0 {
-            let now = Utc::now();
-            sqlx::query!(
-                "UPDATE containers SET updated = ?, location = NULL WHERE location = ?",
-                now,
+    /// Deletes a category with its field definitions. Its items are left without a category.
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn delete_category(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let items = uuids(
+            sqlx::query_scalar!(
+                "SELECT uuid FROM items WHERE category = ? AND deleted_at IS NULL",
                 id
             )
-            .execute(db.deref_mut())
+            .fetch_all(&mut tx)
+            .await?,
+        )?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &items).await?;
+        let result = sqlx::query!("DELETE FROM categories WHERE uuid = ?", id)
+            .execute(&mut tx)
             .await?;
-            Ok(true)
-        } else {
-            Ok(false)
+        if result.rows_affected() == 0 {
+            return Ok(false);
         }
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &items)
+            .await;
+        Ok(true)
     }
-
-    async fn add_container(
+    /// Adds a custom field to a category. Making it required only affects items saved afterwards.
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    #[allow(clippy::too_many_arguments)]
+    async fn add_field(
         &self,
         ctx: &Context<'_>,
-        #[graphql(desc = "Name of the new container")] name: String,
-        #[graphql(desc = "Physical location of container")] location: Uuid,
+        #[graphql(desc = "Primary key of a category")] category: Uuid,
+        name: String,
+        kind: FieldKind,
+        #[graphql(desc = "Unit of numbers")] unit: Option<String>,
+        #[graphql(desc = "Allowed values of enums", default)] options: Vec<String>,
+        #[graphql(default)] required: bool,
     ) -> Result<Uuid, Error> {
+        custom_fields::check_definition(kind, &unit, &options)?;
         let uuid = Uuid::new_v4();
-        let now = Utc::now();
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        sqlx::query!("INSERT INTO containers (uuid, created, updated, name, location) VALUES (?, ?, ?, ?, ?)", uuid, now, now, name, location).execute(db.deref_mut()).await?;
+        let options = serde_json::to_string(&options)?;
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        sqlx::query!(
+            "INSERT INTO field_definitions (uuid, category, name, kind, unit, options, required) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            uuid,
+            category,
+            name,
+            kind,
+            unit,
+            options,
+            required
+        )
+        .execute(db)
+        .await?;
         Ok(uuid)
     }
-    async fn update_container(
+    /// Changes a custom field. Its kind is fixed, values that no longer fit are kept until the
+    /// item is saved again.
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn update_field(
         &self,
         ctx: &Context<'_>,
-        #[graphql(desc = "Primary key of a container")] id: Uuid,
-        #[graphql(desc = "New name")] name: String,
-        #[graphql(desc = "New physical location")] location: Uuid,
+        id: Uuid,
+        name: String,
+        #[graphql(desc = "Unit of numbers")] unit: Option<String>,
+        #[graphql(desc = "Allowed values of enums", default)] options: Vec<String>,
+        #[graphql(default)] required: bool,
     ) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let now = Utc::now();
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let Some(kind) = sqlx::query_scalar!(
+            r#"SELECT kind as "kind: FieldKind" FROM field_definitions WHERE uuid = ?"#,
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        else {
+            return Ok(false);
+        };
+        custom_fields::check_definition(kind, &unit, &options)?;
+        let options = serde_json::to_string(&options)?;
+        // the name is part of the history of items with a value
+        let items = Self::items_with_field(&mut tx, id).await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &items).await?;
         let result = sqlx::query!(
-            "UPDATE containers SET name = ?, location = ?, updated = ? WHERE uuid = ?",
+            "UPDATE field_definitions SET name = ?, unit = ?, options = ?, required = ? WHERE uuid = ?",
             name,
-            location,
-            now,
+            unit,
+            options,
+            required,
             id
         )
-        .execute(db.deref_mut())
+        .execute(&mut tx)
         .await?;
+        let changed = tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &changed)
+            .await;
         Ok(result.rows_affected() > 0)
     }
-    async fn delete_container(
-        &self,
-        ctx: &Context<'_>,
-        #[graphql(desc = "Primary key of a container")] id: Uuid,
-    ) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let result = sqlx::query!("DELETE FROM containers WHERE uuid = ?", id)
-            .execute(db.deref_mut())
+    /// Deletes a custom field along with its values
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn delete_field(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let items = Self::items_with_field(&mut tx, id).await?;
+        let tracker = Tracker::new(&mut tx, Entity::Item, &items).await?;
+        let result = sqlx::query!("DELETE FROM field_definitions WHERE uuid = ?", id)
+            .execute(&mut tx)
             .await?;
-        if result.rows_affected() > 0 {
-            sqlx::query!("DELETE FROM items WHERE container = ?", id)
-                .execute(db.deref_mut())
-                .await?;
-            Ok(true)
-        } else {
-            Ok(false)
+        if result.rows_affected() == 0 {
+            return Ok(false);
         }
+        tracker.record(&mut tx, ctx, ChangeKind::Updated).await?;
+        tx.commit().await?;
+        ctx.data_unchecked::<Arc<Broker>>()
+            .items(ChangeKind::Updated, &items)
+            .await;
+        Ok(true)
     }
 
-    async fn add_item(
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn create_tag(
         &self,
         ctx: &Context<'_>,
-        container: Uuid,
         name: String,
-        quantity: usize,
-        description: Option<String>,
+        #[graphql(desc = "Display colour as `#rrggbb`")] color: Option<String>,
     ) -> Result<Uuid, Error> {
-        let uuid = Uuid::new_v4();
-        let now = Utc::now();
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let quantity = quantity as i64;
-        sqlx::query!("INSERT INTO items (uuid, created, updated, name, description, quantity, container) VALUES (?, ?, ?, ?, ?, ?, ?)", uuid, now, now, name, description, quantity, container).execute(db.deref_mut()).await?;
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let uuid = tags::create(&mut tx, &name, color).await?;
+        tx.commit().await?;
         Ok(uuid)
     }
-    async fn update_item(
+    /// Renames a tag or changes its colour
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn update_tag(
         &self,
         ctx: &Context<'_>,
         id: Uuid,
         name: String,
-        description: Option<String>,
-        quantity: Option<usize>,
+        #[graphql(desc = "Display colour as `#rrggbb`")] color: Option<String>,
     ) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let quantity = quantity.map(|q| q as i64);
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let (items, containers) = tags::tagged_with(&mut tx, &[id]).await?;
+        let trackers = Self::track_tagged(&mut tx, &items, &containers).await?;
+        if !tags::update(&mut tx, id, &name, color).await? {
+            return Ok(false);
+        }
+        Self::record_tagged(&mut tx, ctx, trackers).await?;
+        tx.commit().await?;
+        Self::tags_changed(ctx, &items, &containers).await;
+        Ok(true)
+    }
+    /// Replaces the given tags by `target` everywhere and deletes them
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn merge_tags(
+        &self,
+        ctx: &Context<'_>,
+        sources: Vec<Uuid>,
+        target: Uuid,
+    ) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let (items, containers) = tags::tagged_with(&mut tx, &sources).await?;
+        let trackers = Self::track_tagged(&mut tx, &items, &containers).await?;
+        if !tags::merge(&mut tx, &sources, target).await? {
+            return Ok(false);
+        }
+        Self::record_tagged(&mut tx, ctx, trackers).await?;
+        tx.commit().await?;
+        Self::tags_changed(ctx, &items, &containers).await;
+        Ok(true)
+    }
+    /// Deletes a tag and takes it off all items and containers
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn delete_tag(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let mut tx = ctx.data_unchecked::<MetadataDatabase>().begin().await?;
+        let (items, containers) = tags::tagged_with(&mut tx, &[id]).await?;
+        let trackers = Self::track_tagged(&mut tx, &items, &containers).await?;
+        if !tags::delete(&mut tx, id).await? {
+            return Ok(false);
+        }
+        Self::record_tagged(&mut tx, ctx, trackers).await?;
+        tx.commit().await?;
+        Self::tags_changed(ctx, &items, &containers).await;
+        Ok(true)
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn attach_item_tags(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of an item")] id: Uuid,
+        tags: Vec<Uuid>,
+    ) -> Result<bool, Error> {
+        Self::retag(ctx, Tagged::Item, id, &tags, true).await
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn detach_item_tags(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of an item")] id: Uuid,
+        tags: Vec<Uuid>,
+    ) -> Result<bool, Error> {
+        Self::retag(ctx, Tagged::Item, id, &tags, false).await
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn attach_container_tags(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a container")] id: Uuid,
+        tags: Vec<Uuid>,
+    ) -> Result<bool, Error> {
+        Self::retag(ctx, Tagged::Container, id, &tags, true).await
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn detach_container_tags(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Primary key of a container")] id: Uuid,
+        tags: Vec<Uuid>,
+    ) -> Result<bool, Error> {
+        Self::retag(ctx, Tagged::Container, id, &tags, false).await
+    }
+
+    /// Takes an item, container or location back out of the trash
+    #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+    async fn restore(&self, ctx: &Context<'_>, entity: Entity, id: Uuid) -> Result<bool, Error> {
+        match entity {
+            Entity::Item => Self::restore_item(ctx, id).await,
+            Entity::Container => Self::restore_container(ctx, id).await,
+            Entity::Location => Self::restore_location(ctx, id).await,
+        }
+    }
+
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(SessionGuard)")]
+    async fn create_user(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+        password: String,
+        #[graphql(default_with = "Role::Viewer")] role: Role,
+    ) -> Result<Uuid, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        users::create_user(&mut *db.acquire().await?, &username, &password, role).await
+    }
+    /// Disabled users can't log in, and their active sessions are ended.
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(SessionGuard)")]
+    async fn set_user_disabled(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        disabled: bool,
+    ) -> Result<bool, Error> {
+        anyhow::ensure!(
+            !disabled || ctx.data_unchecked::<User>().id != id,
+            "You can't disable your own account"
+        );
+        let db = ctx.data_unchecked::<MetadataDatabase>();
         let now = Utc::now();
         let result = sqlx::query!(
-            "UPDATE items SET updated = ?, name = ?, description = ?, quantity = ? WHERE uuid = ?",
+            "UPDATE users SET updated = ?, disabled = ? WHERE uuid = ?",
             now,
-            name,
-            description,
-            quantity,
+            disabled,
             id
         )
-        .execute(db.deref_mut())
+        .execute(db)
         .await?;
+        if disabled {
+            user_session::revoke_user_sessions(ctx.data_unchecked::<Arc<FileDatabase>>(), id);
+        }
         Ok(result.rows_affected() > 0)
     }
-    async fn move_item(&self, ctx: &Context<'_>, id: Uuid, container: Uuid) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(SessionGuard)")]
+    async fn set_user_role(&self, ctx: &Context<'_>, id: Uuid, role: Role) -> Result<bool, Error> {
+        anyhow::ensure!(
+            role == Role::Admin || ctx.data_unchecked::<User>().id != id,
+            "You can't revoke your own admin role"
+        );
+        let db = ctx.data_unchecked::<MetadataDatabase>();
         let now = Utc::now();
         let result = sqlx::query!(
-            "UPDATE items SET updated = ?, container = ? WHERE uuid = ?",
+            "UPDATE users SET updated = ?, role = ? WHERE uuid = ?",
             now,
-            container,
+            role,
             id
         )
-        .execute(db.deref_mut())
+        .execute(db)
         .await?;
         Ok(result.rows_affected() > 0)
     }
-    async fn delete_item(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
-        let mut db = ctx.data_unchecked::<MetadataDatabase>().lock().await;
-        let result = sqlx::query!("DELETE FROM items WHERE uuid = ?", id)
-            .execute(db.deref_mut())
-            .await?;
+    /// Ends a session of the currently logged in user. Admins can end any session.
+    #[graphql(guard = "RoleGuard::new(Role::Viewer).and(SessionGuard)")]
+    async fn revoke_session(&self, ctx: &Context<'_>, id: Uuid) -> bool {
+        let user = ctx.data_unchecked::<User>();
+        user_session::revoke_session(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            id,
+            (user.role < Role::Admin).then_some(user.id),
+        )
+    }
+    /// Creates a personal access token for the currently logged in user, for use in an
+    /// `Authorization: Bearer` header.
+    #[graphql(guard = "RoleGuard::new(Role::Viewer).and(SessionGuard)")]
+    async fn create_api_token(
+        &self,
+        ctx: &Context<'_>,
+        label: String,
+        #[graphql(default)] read_only: bool,
+    ) -> Result<CreatedApiToken, Error> {
+        let (token, info) = api_tokens::create(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            ctx.data_unchecked::<User>().id,
+            label,
+            read_only,
+        )?;
+        Ok(CreatedApiToken { token, info })
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Viewer).and(SessionGuard)")]
+    async fn update_api_token_label(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        label: String,
+    ) -> Result<bool, Error> {
+        api_tokens::relabel(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            id,
+            Some(ctx.data_unchecked::<User>().id),
+            label,
+        )
+    }
+    /// Revokes a token of the currently logged in user. Admins can revoke any token.
+    #[graphql(guard = "RoleGuard::new(Role::Viewer).and(SessionGuard)")]
+    async fn revoke_api_token(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool, Error> {
+        let user = ctx.data_unchecked::<User>();
+        api_tokens::revoke(
+            ctx.data_unchecked::<Arc<FileDatabase>>(),
+            id,
+            (user.role < Role::Admin).then_some(user.id),
+        )
+    }
+    /// Changes the password of the currently logged in user
+    #[graphql(guard = "RoleGuard::new(Role::Viewer).and(SessionGuard)")]
+    async fn change_password(
+        &self,
+        ctx: &Context<'_>,
+        current_password: String,
+        new_password: String,
+    ) -> Result<bool, Error> {
+        let user = ctx.data_unchecked::<User>();
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let Some((_, hash)) =
+            users::fetch_credentials(&mut *db.acquire().await?, &user.username).await?
+        else {
+            return Ok(false);
+        };
+        if !users::verify_password_async(hash, current_password).await? {
+            return Ok(false);
+        }
+        let hash = users::hash_password_async(new_password).await?;
+        let now = Utc::now();
+        sqlx::query!(
+            "UPDATE users SET updated = ?, password_hash = ? WHERE uuid = ?",
+            now,
+            hash,
+            user.id
+        )
+        .execute(db)
+        .await?;
+        Ok(true)
+    }
+    #[graphql(guard = "RoleGuard::new(Role::Admin).and(SessionGuard)")]
+    async fn set_user_password(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        password: String,
+    ) -> Result<bool, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let hash = users::hash_password_async(password).await?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            "UPDATE users SET updated = ?, password_hash = ? WHERE uuid = ?",
+            now,
+            hash,
+            id
+        )
+        .execute(db)
+        .await?;
         Ok(result.rows_affected() > 0)
     }
 }
 
+pub struct SubscriptionRoot;
+
+impl SubscriptionRoot {
+    /// Ends a subscription once the session or API token it was started with expires or is
+    /// revoked, or the user is disabled.
+    fn until_revoked<S: Stream>(ctx: &Context<'_>, changes: S) -> impl Stream<Item = S::Item> {
+        let revoked = match (ctx.data_opt::<User>(), ctx.data_opt::<Credential>()) {
+            (Some(user), Some(credential)) => user_session::revoked(
+                ctx.data_unchecked::<Arc<FileDatabase>>().clone(),
+                ctx.data_unchecked::<MetadataDatabase>().clone(),
+                ctx.data_unchecked::<Arc<Config>>().clone(),
+                user.id,
+                *credential,
+            )
+            .left_future(),
+            _ => future::pending().right_future(),
+        };
+        changes.take_until(revoked)
+    }
+}
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Items as they are created, changed, moved or deleted
+    async fn item_changed(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Only changes of this item")] id: Option<Uuid>,
+    ) -> impl Stream<Item = ItemChanged> {
+        let changes = ctx
+            .data_unchecked::<Arc<Broker>>()
+            .changes()
+            .filter_map(move |change| {
+                future::ready(match change {
+                    Change::Item(change) if id.is_none_or(|id| change.id == id) => Some(change),
+                    _ => None,
+                })
+            });
+        Self::until_revoked(ctx, changes)
+    }
+    /// Containers as they are created, changed, moved or deleted
+    async fn container_changed(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Only changes of this container")] id: Option<Uuid>,
+    ) -> impl Stream<Item = ContainerChanged> {
+        let changes = ctx
+            .data_unchecked::<Arc<Broker>>()
+            .changes()
+            .filter_map(move |change| {
+                future::ready(match change {
+                    Change::Container(change) if id.is_none_or(|id| change.id == id) => {
+                        Some(change)
+                    }
+                    _ => None,
+                })
+            });
+        Self::until_revoked(ctx, changes)
+    }
+    /// Locations as they are created, changed, moved or deleted
+    async fn location_changed(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Only changes of this location")] id: Option<Uuid>,
+    ) -> impl Stream<Item = LocationChanged> {
+        let changes = ctx
+            .data_unchecked::<Arc<Broker>>()
+            .changes()
+            .filter_map(move |change| {
+                future::ready(match change {
+                    Change::Location(change) if id.is_none_or(|id| change.id == id) => Some(change),
+                    _ => None,
+                })
+            });
+        Self::until_revoked(ctx, changes)
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct CreatedApiToken {
+    /// The secret token, it can't be retrieved again later
+    pub token: String,
+    pub info: ApiToken,
+}
+
+pub fn location_image_key(id: Uuid) -> Vec<u8> {
+    std::iter::once(LOCATION_IMAGE_TYPE)
+        .chain(id.as_bytes().iter().copied())
+        .collect()
+}
+
+fn uuids(rows: Vec<Vec<u8>>) -> Result<Vec<Uuid>, Error> {
+    Ok(rows
+        .iter()
+        .map(|uuid| Uuid::from_slice(uuid))
+        .collect::<Result<_, _>>()?)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
 pub struct Location {
     pub id: Uuid,
     pub name: String,
+    pub description: Option<String>,
+    #[graphql(skip)]
+    pub parent_id: Option<Uuid>,
+}
+
+#[ComplexObject]
+impl Location {
+    /// The location this one is part of
+    async fn parent(&self, ctx: &Context<'_>) -> Result<Option<Location>, Error> {
+        if let Some(parent) = self.parent_id {
+            Ok(ctx
+                .data_unchecked::<DataLoader<LocationLoader>>()
+                .load_one(parent)
+                .await?)
+        } else {
+            Ok(None)
+        }
+    }
+    /// Locations directly within this one
+    async fn children(&self, ctx: &Context<'_>) -> Result<Vec<Location>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<LocationChildrenLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// All locations from the top-level one down to this one
+    async fn path(&self, ctx: &Context<'_>) -> Result<Vec<Location>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            LocationRow,
+            r#"WITH RECURSIVE ancestors(uuid, depth) AS (
+                SELECT ?, 0
+                UNION SELECT l.parent, a.depth + 1 FROM locations l JOIN ancestors a ON l.uuid = a.uuid
+                WHERE l.parent IS NOT NULL AND a.depth < 100
+            )
+            SELECT l.uuid as "uuid!", l.name as "name!", l.description, l.parent
+            FROM ancestors a JOIN locations l ON l.uuid = a.uuid ORDER BY a.depth DESC"#,
+            self.id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Location::from).collect())
+    }
+    /// Whether a floor plan was uploaded to `/image/location/{id}`
+    async fn has_floor_plan(&self, ctx: &Context<'_>) -> Result<bool, Error> {
+        Ok(ctx
+            .data_unchecked::<Arc<FileDatabase>>()
+            .get(location_image_key(self.id))?
+            .is_some())
+    }
+    /// Changes to this location, newest first
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: usize,
+    ) -> Result<Vec<Event>, Error> {
+        history::of_entity(ctx.data_unchecked::<MetadataDatabase>(), self.id, limit).await
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
 pub struct Container {
     pub id: Uuid,
     pub created: DateTime<Utc>,
     pub updated: DateTime<Utc>,
     pub name: Option<String>,
-    pub location: Option<Location>,
+    #[graphql(skip)]
+    pub location_id: Option<Uuid>,
+    #[graphql(skip)]
+    pub parent_id: Option<Uuid>,
+}
+
+#[ComplexObject]
+impl Container {
+    /// Physical location, the same as the outer container's for nested containers
+    async fn location(&self, ctx: &Context<'_>) -> Result<Option<Location>, Error> {
+        if let Some(location) = self.location_id {
+            Ok(ctx
+                .data_unchecked::<DataLoader<LocationLoader>>()
+                .load_one(location)
+                .await?)
+        } else {
+            Ok(None)
+        }
+    }
+    /// The container this one is in
+    async fn parent(&self, ctx: &Context<'_>) -> Result<Option<Container>, Error> {
+        if let Some(parent) = self.parent_id {
+            Ok(ctx
+                .data_unchecked::<DataLoader<ContainerLoader>>()
+                .load_one(parent)
+                .await?)
+        } else {
+            Ok(None)
+        }
+    }
+    /// Containers directly inside this one
+    async fn children(&self, ctx: &Context<'_>) -> Result<Vec<Container>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ContainerChildrenLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// All containers from the outermost one down to this one
+    async fn path(&self, ctx: &Context<'_>) -> Result<Vec<Container>, Error> {
+        let db = ctx.data_unchecked::<MetadataDatabase>();
+        let rows = sqlx::query_as!(
+            ContainerRow,
+            r#"WITH RECURSIVE ancestors(uuid, depth) AS (
+                SELECT ?, 0
+                UNION SELECT c.parent, a.depth + 1 FROM containers c JOIN ancestors a ON c.uuid = a.uuid
+                WHERE c.parent IS NOT NULL AND a.depth < 100
+            )
+            SELECT c.uuid as "uuid!", c.created as "created!", c.updated as "updated!", c.name, c.location, c.parent
+            FROM ancestors a JOIN containers c ON c.uuid = a.uuid ORDER BY a.depth DESC"#,
+            self.id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows.into_iter().map(Container::from).collect())
+    }
+    /// Image gallery of this container, in order
+    async fn images(&self, ctx: &Context<'_>) -> Result<Vec<Image>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ContainerImagesLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// Thumbnail of the primary image of this container, resized to fit `size`
+    async fn thumbnail_url(&self, ctx: &Context<'_>, size: u32) -> Result<Option<String>, Error> {
+        let images = ctx
+            .data_unchecked::<DataLoader<ContainerImagesLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default();
+        Ok(images
+            .iter()
+            .find(|image| image.primary)
+            .or_else(|| images.first())
+            .map(|image| image.sized_url(size)))
+    }
+    /// Tags of this container, sorted by name
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<Tag>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ContainerTagsLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// Changes to this container, newest first
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: usize,
+    ) -> Result<Vec<Event>, Error> {
+        history::of_entity(ctx.data_unchecked::<MetadataDatabase>(), self.id, limit).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum SearchKind {
+    Item,
+    Container,
+    Location,
+}
+
+/// Escapes text marked up by `highlight()` or `snippet()` for HTML, and only then turns the
+/// markers around the matches into `<b>` tags.
+fn highlight_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\u{2}' => html.push_str("<b>"),
+            '\u{3}' => html.push_str("</b>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct SearchHit {
+    pub kind: SearchKind,
+    pub id: Uuid,
+    /// HTML escaped name with the matching words wrapped in `<b>` tags
+    pub highlighted_name: String,
+    /// HTML escaped excerpt of the description around the matching words, wrapped in `<b>` tags
+    pub highlighted_description: Option<String>,
+    /// Relevance of the hit, higher is better
+    pub rank: f64,
+}
+
+#[ComplexObject]
+impl SearchHit {
+    async fn item(&self, ctx: &Context<'_>) -> Result<Option<Item>, Error> {
+        if self.kind == SearchKind::Item {
+            QueryRoot.item(ctx, self.id).await
+        } else {
+            Ok(None)
+        }
+    }
+    /// The container that was found, or the one the item is in
+    async fn container(&self, ctx: &Context<'_>) -> Result<Option<Container>, Error> {
+        match self.kind {
+            SearchKind::Item => match self.item(ctx).await? {
+                Some(item) => Ok(Some(item.container(ctx).await?)),
+                None => Ok(None),
+            },
+            SearchKind::Container => QueryRoot.container(ctx, self.id).await,
+            SearchKind::Location => Ok(None),
+        }
+    }
+    /// The location that was found, or the one the container is at
+    async fn location(&self, ctx: &Context<'_>) -> Result<Option<Location>, Error> {
+        match self.kind {
+            SearchKind::Location => QueryRoot.location(ctx, self.id).await,
+            _ => match self.container(ctx).await? {
+                Some(container) => container.location(ctx).await,
+                None => Ok(None),
+            },
+        }
+    }
+    /// Containers from the outermost one down to `container`
+    async fn container_path(&self, ctx: &Context<'_>) -> Result<Vec<Container>, Error> {
+        match self.container(ctx).await? {
+            Some(container) => container.path(ctx).await,
+            None => Ok(Vec::new()),
+        }
+    }
+    /// Locations from the top-level one down to `location`
+    async fn location_path(&self, ctx: &Context<'_>) -> Result<Vec<Location>, Error> {
+        match self.location(ctx).await? {
+            Some(location) => location.path(ctx).await,
+            None => Ok(Vec::new()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
 pub struct Item {
     pub id: Uuid,
     pub created: DateTime<Utc>,
     pub updated: DateTime<Utc>,
     pub name: String,
     pub quantity: usize,
+    /// Stock is reported as low below this quantity
+    pub min_quantity: Option<usize>,
+    pub expires_at: Option<NaiveDate>,
     pub description: Option<String>,
-    pub container: Container,
+    #[graphql(skip)]
+    pub container_id: Uuid,
+    #[graphql(skip)]
+    pub category_id: Option<Uuid>,
+    pub purchase: Purchase,
+}
+
+#[ComplexObject]
+impl Item {
+    async fn container(&self, ctx: &Context<'_>) -> Result<Container, Error> {
+        ctx.data_unchecked::<DataLoader<ContainerLoader>>()
+            .load_one(self.container_id)
+            .await?
+            .ok_or_else(|| sqlx::Error::RowNotFound.into())
+    }
+    async fn category(&self, ctx: &Context<'_>) -> Result<Option<Category>, Error> {
+        if let Some(category) = self.category_id {
+            Ok(ctx
+                .data_unchecked::<DataLoader<CategoryLoader>>()
+                .load_one(category)
+                .await?)
+        } else {
+            Ok(None)
+        }
+    }
+    /// Values of the custom fields of the item's category, in the order they were defined
+    async fn fields(&self, ctx: &Context<'_>) -> Result<Vec<FieldValue>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ItemFieldsLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// Image gallery of this item, in order
+    async fn images(&self, ctx: &Context<'_>) -> Result<Vec<Image>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ItemImagesLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// Thumbnail of the primary image of this item, resized to fit `size`
+    async fn thumbnail_url(&self, ctx: &Context<'_>, size: u32) -> Result<Option<String>, Error> {
+        let images = ctx
+            .data_unchecked::<DataLoader<ItemImagesLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default();
+        Ok(images
+            .iter()
+            .find(|image| image.primary)
+            .or_else(|| images.first())
+            .map(|image| image.sized_url(size)))
+    }
+    /// Tags of this item, sorted by name
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<Tag>, Error> {
+        Ok(ctx
+            .data_unchecked::<DataLoader<ItemTagsLoader>>()
+            .load_one(self.id)
+            .await?
+            .unwrap_or_default())
+    }
+    /// Changes to this item, newest first
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: usize,
+    ) -> Result<Vec<Event>, Error> {
+        history::of_entity(ctx.data_unchecked::<MetadataDatabase>(), self.id, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::Request;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn user_management_needs_a_session() {
+        let schema = Schema::new(QueryRoot, MutationRoot, SubscriptionRoot);
+        let id = Uuid::new_v4();
+        for mutation in [
+            r#"mutation { createUser(username: "someone", password: "secret", role: ADMIN) }"#
+                .to_owned(),
+            format!(
+                r#"mutation {{ setUserDisabled(id: "{}", disabled: true) }}"#,
+                id
+            ),
+            format!(r#"mutation {{ setUserRole(id: "{}", role: ADMIN) }}"#, id),
+        ] {
+            let response = schema
+                .execute(
+                    Request::new(mutation.as_str())
+                        .data(users::test_user(Role::Admin))
+                        .data(Credential::ApiToken(Uuid::new_v4())),
+                )
+                .await;
+            assert_eq!(response.errors.len(), 1, "{}", mutation);
+            assert_eq!(
+                response.errors[0].message, "Not allowed with an API token",
+                "{}",
+                mutation
+            );
+        }
+    }
+
+    #[test]
+    fn highlight_html_escapes_text_but_not_the_matches() {
+        assert_eq!(
+            highlight_html("<img src=x onerror=\"alert('\u{2}hi\u{3}')\"> & more"),
+            "&lt;img src=x onerror=&quot;alert(&#39;<b>hi</b>&#39;)&quot;&gt; &amp; more"
+        );
+    }
 }
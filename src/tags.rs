@@ -0,0 +1,259 @@
+//! Tags to categorise items and containers beyond the container they're in.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{loaders, MetadataDatabase};
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct Tag {
+    pub id: Uuid,
+    pub name: String,
+    /// Display colour as `#rrggbb`
+    pub color: Option<String>,
+}
+
+/// Columns of the `tags` table
+#[derive(sqlx::FromRow)]
+pub(crate) struct TagRow {
+    uuid: Vec<u8>,
+    name: String,
+    color: Option<String>,
+}
+
+impl From<TagRow> for Tag {
+    fn from(row: TagRow) -> Self {
+        Tag {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            name: row.name,
+            color: row.color,
+        }
+    }
+}
+
+/// A tag together with the item or container it's attached to
+#[derive(sqlx::FromRow)]
+pub(crate) struct TaggedRow {
+    owner: Vec<u8>,
+    uuid: Vec<u8>,
+    name: String,
+    color: Option<String>,
+}
+
+/// Tags by the object they're attached to, sorted by name.
+pub(crate) fn group(rows: Vec<TaggedRow>) -> HashMap<Uuid, Vec<Tag>> {
+    let mut tags: HashMap<Uuid, Vec<Tag>> = HashMap::new();
+    for row in rows {
+        tags.entry(Uuid::from_slice(&row.owner).unwrap())
+            .or_default()
+            .push(Tag::from(TagRow {
+                uuid: row.uuid,
+                name: row.name,
+                color: row.color,
+            }));
+    }
+    for tags in tags.values_mut() {
+        tags.sort_by_cached_key(|tag| tag.name.to_lowercase());
+    }
+    tags
+}
+
+/// Objects that can be tagged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tagged {
+    Item,
+    Container,
+}
+
+impl Tagged {
+    /// Table of the tagged objects, the link table and its column referring to them
+    fn tables(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Tagged::Item => ("items", "item_tags", "item"),
+            Tagged::Container => ("containers", "container_tags", "container"),
+        }
+    }
+}
+
+fn check_color(color: &Option<String>) -> Result<(), Error> {
+    if let Some(color) = color {
+        anyhow::ensure!(
+            color.len() == 7
+                && color.starts_with('#')
+                && color[1..].chars().all(|c| c.is_ascii_hexdigit()),
+            "Colours have to be given as #rrggbb"
+        );
+    }
+    Ok(())
+}
+
+pub async fn list(db: &MetadataDatabase) -> Result<Vec<Tag>, Error> {
+    let rows = sqlx::query_as!(
+        TagRow,
+        "SELECT uuid, name, color FROM tags ORDER BY name COLLATE NOCASE"
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(Tag::from).collect())
+}
+
+pub async fn create(
+    db: &mut SqliteConnection,
+    name: &str,
+    color: Option<String>,
+) -> Result<Uuid, Error> {
+    check_color(&color)?;
+    let uuid = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO tags (uuid, name, color) VALUES (?, ?, ?)",
+        uuid,
+        name,
+        color
+    )
+    .execute(db)
+    .await?;
+    Ok(uuid)
+}
+
+pub async fn update(
+    db: &mut SqliteConnection,
+    id: Uuid,
+    name: &str,
+    color: Option<String>,
+) -> Result<bool, Error> {
+    check_color(&color)?;
+    let result = sqlx::query!(
+        "UPDATE tags SET name = ?, color = ? WHERE uuid = ?",
+        name,
+        color,
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Items and containers that have any of the given tags.
+pub async fn tagged_with(
+    db: &mut SqliteConnection,
+    tags: &[Uuid],
+) -> Result<(Vec<Uuid>, Vec<Uuid>), Error> {
+    let items: Vec<(Vec<u8>,)> = loaders::fetch_all(
+        &mut *db,
+        "SELECT DISTINCT item FROM item_tags WHERE tag IN",
+        tags,
+    )
+    .await?;
+    let containers: Vec<(Vec<u8>,)> = loaders::fetch_all(
+        &mut *db,
+        "SELECT DISTINCT container FROM container_tags WHERE tag IN",
+        tags,
+    )
+    .await?;
+    let uuids = |rows: Vec<(Vec<u8>,)>| {
+        rows.iter()
+            .map(|(uuid,)| Uuid::from_slice(uuid))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok((uuids(items)?, uuids(containers)?))
+}
+
+/// Attaches `target` everywhere one of `sources` is attached, then deletes the sources.
+pub async fn merge(
+    db: &mut SqliteConnection,
+    sources: &[Uuid],
+    target: Uuid,
+) -> Result<bool, Error> {
+    if sqlx::query_scalar!("SELECT COUNT(*) FROM tags WHERE uuid = ?", target)
+        .fetch_one(&mut *db)
+        .await?
+        == 0
+    {
+        return Ok(false);
+    }
+    for source in sources.iter().filter(|source| **source != target) {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO item_tags (item, tag) SELECT item, ? FROM item_tags WHERE tag = ?",
+            target,
+            source
+        )
+        .execute(&mut *db)
+        .await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO container_tags (container, tag) SELECT container, ? FROM container_tags WHERE tag = ?",
+            target,
+            source
+        )
+        .execute(&mut *db)
+        .await?;
+        // removes the links along with it
+        sqlx::query!("DELETE FROM tags WHERE uuid = ?", source)
+            .execute(&mut *db)
+            .await?;
+    }
+    Ok(true)
+}
+
+pub async fn delete(db: &mut SqliteConnection, id: Uuid) -> Result<bool, Error> {
+    let result = sqlx::query!("DELETE FROM tags WHERE uuid = ?", id)
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Attaches tags to an item or container, unless it doesn't exist or is in the trash.
+pub async fn attach(
+    db: &mut SqliteConnection,
+    tagged: Tagged,
+    id: Uuid,
+    tags: &[Uuid],
+) -> Result<bool, Error> {
+    let (objects, links, column) = tagged.tables();
+    let mut exists = QueryBuilder::new(format!(
+        "SELECT COUNT(*) > 0 FROM {} WHERE deleted_at IS NULL AND uuid = ",
+        objects
+    ));
+    exists.push_bind(id);
+    let (exists,): (bool,) = exists.build_query_as().fetch_one(&mut *db).await?;
+    if !exists {
+        return Ok(false);
+    }
+    if tags.is_empty() {
+        return Ok(true);
+    }
+    let mut query = QueryBuilder::new(format!(
+        "INSERT OR IGNORE INTO {} ({}, tag) ",
+        links, column
+    ));
+    query.push_values(tags, |mut row, tag| {
+        row.push_bind(id).push_bind(*tag);
+    });
+    query.build().execute(db).await?;
+    Ok(true)
+}
+
+/// Removes tags from an item or container. Returns whether any of them were attached.
+pub async fn detach(
+    db: &mut SqliteConnection,
+    tagged: Tagged,
+    id: Uuid,
+    tags: &[Uuid],
+) -> Result<bool, Error> {
+    if tags.is_empty() {
+        return Ok(false);
+    }
+    let (_, links, column) = tagged.tables();
+    let mut query = QueryBuilder::new(format!("DELETE FROM {} WHERE {} = ", links, column));
+    query.push_bind(id).push(" AND tag IN (");
+    let mut separated = query.separated(", ");
+    for tag in tags {
+        separated.push_bind(*tag);
+    }
+    separated.push_unseparated(")");
+    Ok(query.build().execute(db).await?.rows_affected() > 0)
+}
@@ -0,0 +1,286 @@
+//! Resized variants of images. Requested sizes are snapped to the configured thumbnail sizes, the
+//! square thumbnails are rendered right at upload and kept in the file database next to the
+//! original, any other variant is rendered on demand.
+
+use anyhow::Error;
+use image::{imageops::FilterType, DynamicImage};
+use rocksdb::WriteBatch;
+use serde::Deserialize;
+
+use crate::{
+    config::Images,
+    http_cache::{self, BlobInfo},
+    image_formats::{self, ImageFormat},
+    schema::VARIANT_TYPE,
+    FileDatabase,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scaled to fit inside, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// Scaled to cover all of it, cropping what's outside
+    Cover,
+    /// Stretched to exactly that size
+    Fill,
+}
+
+/// Requested size of an image, as `?w=&h=&fit=`. A missing side follows the aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Variant {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+}
+
+impl Variant {
+    pub fn square(size: u32) -> Self {
+        Variant {
+            w: Some(size),
+            h: Some(size),
+            fit: Fit::Contain,
+        }
+    }
+
+    /// Whether anything other than the original was asked for
+    pub fn is_resized(&self) -> bool {
+        self.w.is_some() || self.h.is_some()
+    }
+
+    /// The variant that's actually rendered: both sides are rounded up to one of the configured
+    /// sizes, or down to the largest one. `None` if no sizes are configured.
+    fn snapped(&self, sizes: &[u32]) -> Option<Self> {
+        let largest = sizes.iter().copied().filter(|size| *size > 0).max()?;
+        let snap = |side: Option<u32>| {
+            side.map(|side| {
+                sizes
+                    .iter()
+                    .copied()
+                    .filter(|size| *size >= side.max(1))
+                    .min()
+                    .unwrap_or(largest)
+            })
+        };
+        let (w, h) = (snap(self.w), snap(self.h));
+        // the fit only matters when both sides are given
+        let fit = if w.is_some() && h.is_some() {
+            self.fit
+        } else {
+            Fit::Contain
+        };
+        Some(Variant { w, h, fit })
+    }
+
+    /// Whether it's one of the thumbnails rendered on upload, which are the only ones kept.
+    fn is_thumbnail(&self, sizes: &[u32]) -> bool {
+        sizes.iter().any(|size| *self == Variant::square(*size))
+    }
+}
+
+/// Key of a variant of the image stored under `source`.
+fn variant_key(source: &[u8], variant: &Variant) -> Vec<u8> {
+    std::iter::once(VARIANT_TYPE)
+        .chain(source.iter().copied())
+        .chain(variant.w.unwrap_or(0).to_be_bytes())
+        .chain(variant.h.unwrap_or(0).to_be_bytes())
+        .chain(std::iter::once(variant.fit as u8))
+        .collect()
+}
+
+/// Scales an image down to a variant, `None` if it's small enough already. Images are never
+/// scaled up, the requested size is shrunk to what the image covers instead.
+fn render(
+    image: &DynamicImage,
+    variant: &Variant,
+    config: &Images,
+) -> Result<Option<Vec<u8>>, Error> {
+    let (width, height) = (image.width(), image.height());
+    let (w, h) = (variant.w.unwrap_or(u32::MAX), variant.h.unwrap_or(u32::MAX));
+    let resized = match variant.fit {
+        Fit::Contain if width <= w && height <= h => return Ok(None),
+        Fit::Contain => image.resize(w, h, FilterType::Lanczos3),
+        Fit::Cover | Fit::Fill => {
+            // keeps the aspect ratio of the requested size
+            let scale = (width as f64 / w as f64)
+                .min(height as f64 / h as f64)
+                .min(1.0);
+            let (w, h) = (
+                ((w as f64 * scale).round() as u32).max(1),
+                ((h as f64 * scale).round() as u32).max(1),
+            );
+            if (w, h) == (width, height) {
+                return Ok(None);
+            }
+            match variant.fit {
+                Fit::Cover => image.resize_to_fill(w, h, FilterType::Lanczos3),
+                _ => image.resize_exact(w, h, FilterType::Lanczos3),
+            }
+        }
+    };
+    let format = if resized.color().has_alpha() {
+        ImageFormat::Png
+    } else {
+        ImageFormat::Jpeg
+    };
+    Ok(Some(image_formats::encode(&resized, format, config)?))
+}
+
+/// Renders the configured thumbnails of a freshly uploaded image, unless it can't be decoded.
+pub fn generate(
+    files: &FileDatabase,
+    source: &[u8],
+    data: &[u8],
+    config: &Images,
+) -> Result<(), Error> {
+    let Some(format) = ImageFormat::sniff(data) else {
+        return Ok(());
+    };
+    let Some(image) = image_formats::decode(data, format)? else {
+        return Ok(());
+    };
+    let mut batch = WriteBatch::default();
+    for size in &config.thumbnail_sizes {
+        let variant = Variant::square(*size);
+        if let Some(rendered) = render(&image, &variant, config)? {
+            http_cache::put(&mut batch, &variant_key(source, &variant), &rendered)?;
+        }
+    }
+    files.write(batch)?;
+    Ok(())
+}
+
+/// Outcome of looking up a variant
+pub enum Lookup {
+    Found(Vec<u8>, BlobInfo),
+    /// There's no such image
+    Missing,
+    /// The image can't be decoded, so it can't be resized either
+    Unsupported,
+}
+
+/// A variant of the image stored under `source`. Only the configured thumbnails are kept, other
+/// sizes are rendered on every request. Images smaller than asked for are returned as they are.
+pub fn fetch(
+    files: &FileDatabase,
+    source: &[u8],
+    variant: &Variant,
+    config: &Images,
+) -> Result<Lookup, Error> {
+    let Some((data, info)) = http_cache::load(files, source)? else {
+        return Ok(Lookup::Missing);
+    };
+    let Some(variant) = variant.snapped(&config.thumbnail_sizes) else {
+        return Ok(Lookup::Found(data, info));
+    };
+    let key = variant_key(source, &variant);
+    let keep = variant.is_thumbnail(&config.thumbnail_sizes);
+    if keep {
+        if let Some((data, info)) = http_cache::load(files, &key)? {
+            return Ok(Lookup::Found(data, info));
+        }
+    }
+    let Some(format) = ImageFormat::sniff(&data) else {
+        return Ok(Lookup::Unsupported);
+    };
+    let Some(image) = image_formats::decode(&data, format)? else {
+        return Ok(Lookup::Unsupported);
+    };
+    match render(&image, &variant, config)? {
+        None => Ok(Lookup::Found(data, info)),
+        Some(rendered) if keep => {
+            let info = http_cache::store(files, &key, &rendered)?;
+            Ok(Lookup::Found(rendered, info))
+        }
+        Some(rendered) => {
+            // changes whenever the original does
+            let info = BlobInfo {
+                modified: info.modified,
+                ..BlobInfo::new(&rendered)
+            };
+            Ok(Lookup::Found(rendered, info))
+        }
+    }
+}
+
+/// Deletes all variants of the image stored under `source`, once it's gone or replaced.
+pub fn delete_variants(files: &FileDatabase, source: &[u8]) -> Result<(), Error> {
+    let prefix: Vec<u8> = std::iter::once(VARIANT_TYPE)
+        .chain(source.iter().copied())
+        .collect();
+    let mut batch = WriteBatch::default();
+    for (key, _) in files
+        .prefix_iterator(&prefix)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+    {
+        http_cache::delete(&mut batch, &key);
+    }
+    files.write(batch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    fn variant(w: Option<u32>, h: Option<u32>, fit: Fit) -> Variant {
+        Variant { w, h, fit }
+    }
+
+    #[test]
+    fn snapped_rounds_up_to_configured_sizes() {
+        let sizes = [64, 256];
+        assert_eq!(
+            variant(Some(100), Some(10), Fit::Cover).snapped(&sizes),
+            Some(variant(Some(256), Some(64), Fit::Cover))
+        );
+        assert_eq!(
+            variant(Some(4000), None, Fit::Cover).snapped(&sizes),
+            Some(variant(Some(256), None, Fit::Contain))
+        );
+        assert_eq!(
+            variant(Some(0), Some(64), Fit::Contain).snapped(&sizes),
+            Some(Variant::square(64))
+        );
+        assert_eq!(Variant::square(64).snapped(&[]), None);
+    }
+
+    #[test]
+    fn only_square_thumbnails_are_kept() {
+        let sizes = [64, 256];
+        assert!(Variant::square(256).is_thumbnail(&sizes));
+        assert!(!variant(Some(256), Some(256), Fit::Cover).is_thumbnail(&sizes));
+        assert!(!variant(Some(256), None, Fit::Contain).is_thumbnail(&sizes));
+    }
+
+    #[test]
+    fn render_never_scales_up() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(100, 50));
+        let config = Images::default();
+        assert!(render(&image, &Variant::square(256), &config)
+            .unwrap()
+            .is_none());
+        assert!(
+            render(&image, &variant(Some(100), Some(50), Fit::Fill), &config)
+                .unwrap()
+                .is_none()
+        );
+
+        let rendered = render(&image, &variant(Some(256), Some(256), Fit::Cover), &config)
+            .unwrap()
+            .unwrap();
+        let rendered = image::load_from_memory(&rendered).unwrap();
+        // the square shrunk to what the image covers
+        assert_eq!((rendered.width(), rendered.height()), (50, 50));
+
+        let rendered = render(&image, &Variant::square(64), &config)
+            .unwrap()
+            .unwrap();
+        let rendered = image::load_from_memory(&rendered).unwrap();
+        assert_eq!((rendered.width(), rendered.height()), (64, 32));
+    }
+}
@@ -0,0 +1,144 @@
+//! Deleted items, containers and locations are kept in the trash for a while, so that they can
+//! be restored. Afterwards they're purged together with their images.
+
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use anyhow::Error;
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    config::Config, gallery, history::Entity, http_cache, schema::location_image_key, thumbnails,
+    FileDatabase, MetadataDatabase,
+};
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct TrashEntry {
+    pub entity: Entity,
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub deleted_at: DateTime<Utc>,
+    /// When it is removed for good
+    pub purge_at: DateTime<Utc>,
+}
+
+/// Everything in the trash, most recently deleted first. Objects that were deleted along with
+/// their container are restored with it, so they aren't listed on their own.
+pub async fn list(db: &MetadataDatabase, config: &Config) -> Result<Vec<TrashEntry>, Error> {
+    let retention = Duration::days(config.database.trash_retention_days.into());
+    let rows = sqlx::query!(
+        r#"SELECT 'item' as "entity!: Entity", uuid as "uuid!: Vec<u8>", name as "name: String",
+            deleted_at as "deleted_at!: NaiveDateTime"
+        FROM items i WHERE deleted_at IS NOT NULL
+            AND deleted_at IS NOT (SELECT deleted_at FROM containers WHERE uuid = i.container)
+        UNION ALL SELECT 'container', uuid, name, deleted_at
+        FROM containers c WHERE deleted_at IS NOT NULL
+            AND deleted_at IS NOT (SELECT deleted_at FROM containers WHERE uuid = c.parent)
+        UNION ALL SELECT 'location', uuid, name, deleted_at
+        FROM locations WHERE deleted_at IS NOT NULL
+        ORDER BY 4 DESC"#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let deleted_at = DateTime::from_utc(row.deleted_at, Utc);
+            TrashEntry {
+                entity: row.entity,
+                id: Uuid::from_slice(&row.uuid).unwrap(),
+                name: row.name,
+                deleted_at,
+                purge_at: deleted_at + retention,
+            }
+        })
+        .collect())
+}
+
+/// Removes everything that was deleted before `cutoff`. Returns the number of purged objects.
+pub async fn purge(
+    files: &FileDatabase,
+    db: &MetadataDatabase,
+    cutoff: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let mut tx = db.begin().await?;
+    let items = sqlx::query_scalar!("SELECT uuid FROM items WHERE deleted_at < ?", cutoff)
+        .fetch_all(&mut tx)
+        .await?;
+    let containers =
+        sqlx::query_scalar!("SELECT uuid FROM containers WHERE deleted_at < ?", cutoff)
+            .fetch_all(&mut tx)
+            .await?;
+    // the metadata goes along with the items and containers
+    let images = sqlx::query_scalar!(
+        "SELECT uuid FROM images WHERE item IN (SELECT uuid FROM items WHERE deleted_at < ?1)
+        OR container IN (SELECT uuid FROM containers WHERE deleted_at < ?1)",
+        cutoff
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    let locations = sqlx::query_scalar!("SELECT uuid FROM locations WHERE deleted_at < ?", cutoff)
+        .fetch_all(&mut tx)
+        .await?;
+    // whatever stays can't refer to what's purged, such rows are moved to the top level
+    sqlx::query!(
+        "UPDATE containers SET parent = NULL
+        WHERE parent IN (SELECT uuid FROM containers WHERE deleted_at < ?1)
+            AND (deleted_at IS NULL OR deleted_at >= ?1)",
+        cutoff
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE containers SET location = NULL
+        WHERE location IN (SELECT uuid FROM locations WHERE deleted_at < ?1)
+            AND (deleted_at IS NULL OR deleted_at >= ?1)",
+        cutoff
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE locations SET parent = NULL
+        WHERE parent IN (SELECT uuid FROM locations WHERE deleted_at < ?1)
+            AND (deleted_at IS NULL OR deleted_at >= ?1)",
+        cutoff
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!("DELETE FROM items WHERE deleted_at < ?", cutoff)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!("DELETE FROM containers WHERE deleted_at < ?", cutoff)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!("DELETE FROM locations WHERE deleted_at < ?", cutoff)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let images = images
+        .iter()
+        .map(|uuid| Uuid::from_slice(uuid))
+        .collect::<Result<Vec<_>, _>>()?;
+    gallery::delete_blobs(files, &images)?;
+    for location in &locations {
+        let key = location_image_key(Uuid::from_slice(location)?);
+        http_cache::remove(files, &key)?;
+        thumbnails::delete_variants(files, &key)?;
+    }
+    Ok(items.len() + containers.len() + locations.len())
+}
+
+pub async fn sweep_trash(files: Arc<FileDatabase>, db: MetadataDatabase, config: Arc<Config>) {
+    let retention = Duration::days(config.database.trash_retention_days.into());
+    let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match purge(&files, &db, Utc::now() - retention).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Purged {} objects from the trash.", count),
+            Err(err) => log::error!("Failed purging the trash: {}", err),
+        }
+    }
+}
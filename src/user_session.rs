@@ -1,37 +1,199 @@
-use std::sync::Arc;
+use std::{
+    fmt::Display,
+    sync::{Arc, OnceLock},
+    time::Duration as StdDuration,
+};
 
 use actix_session::Session;
-use actix_web::{error::ErrorInternalServerError, post, web, HttpResponse};
-use serde::Deserialize;
+use actix_web::{
+    error::ErrorInternalServerError,
+    http::{header, StatusCode},
+    post, web, HttpRequest, HttpResponse, ResponseError,
+};
+use async_graphql::{Context, Guard, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{config::Config, schema::SESSION_TYPE, FileDatabase};
+use crate::{
+    api_tokens,
+    config::{self, Config},
+    login_attempts,
+    schema::SESSION_TYPE,
+    users::{self, Role, User},
+    FileDatabase, MetadataDatabase,
+};
 
 #[derive(Deserialize)]
 pub struct LoginFormData {
+    pub username: String,
     pub password: String,
 }
 
-// fetch("/login", { method: "POST", body: new URLSearchParams({ password: "..." }) })
+/// Value stored in the file database for every active session.
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(name = "Session")]
+pub struct SessionData {
+    /// Identifies the session without revealing the cookie token
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user: Uuid,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl SessionData {
+    fn is_expired(&self, config: &config::Auth, now: DateTime<Utc>) -> bool {
+        now - self.last_seen > Duration::days(config.session_idle_days.into())
+            || now - self.created > Duration::days(config.session_max_age_days.into())
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized,
+    Forbidden,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthorized => write!(f, "Unauthorized"),
+            AuthError::Forbidden => write!(f, "Forbidden"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AuthError::Unauthorized => HttpResponse::Unauthorized().content_type("text/html").body(
+                LOGIN_PAGE
+                    .get()
+                    .map_or(include_str!("../login.html"), String::as_str),
+            ),
+            AuthError::Forbidden => HttpResponse::Forbidden().body("Forbidden"),
+        }
+    }
+}
+
+static LOGIN_PAGE: OnceLock<String> = OnceLock::new();
+
+/// Prepares the login page, offering single sign-on if it's configured.
+pub fn init_login_page(single_sign_on: bool) {
+    let page = if single_sign_on {
+        include_str!("../login.html").replace(
+            "<!-- single sign-on -->",
+            r#"<p><a href="/login/oidc">Log in with single sign-on</a></p>"#,
+        )
+    } else {
+        include_str!("../login.html").to_owned()
+    };
+    LOGIN_PAGE.set(page).ok();
+}
+
+fn session_key(token: &Uuid) -> Vec<u8> {
+    std::iter::once(SESSION_TYPE)
+        .chain(token.as_bytes().iter().copied())
+        .collect()
+}
+
+/// Logs the user in by storing a new session and handing out its token as a cookie.
+pub fn start_session(
+    session: &Session,
+    db: &FileDatabase,
+    user: Uuid,
+    req: &HttpRequest,
+) -> Result<(), actix_web::Error> {
+    let token = Uuid::new_v4();
+    let now = Utc::now();
+    let data = serde_json::to_vec(&SessionData {
+        id: Uuid::new_v4(),
+        user,
+        created: now,
+        last_seen: now,
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned),
+        ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(ToOwned::to_owned),
+    })
+    .map_err(ErrorInternalServerError)?;
+    db.put(session_key(&token), data)
+        .map_err(ErrorInternalServerError)?;
+    session.insert("auth", token)?;
+    Ok(())
+}
+
+// fetch("/login", { method: "POST", body: new URLSearchParams({ username: "...", password: "..." }) })
 #[post("/login")]
 pub async fn login(
     session: Session,
     db: web::Data<Arc<FileDatabase>>,
+    metadata: web::Data<MetadataDatabase>,
     config: web::Data<Arc<Config>>,
     form: web::Form<LoginFormData>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
-    // this is not really secure, since it's O(n) with n = first difference
-    if form.password == config.auth.password {
-        let token = Uuid::new_v4();
-        let key: Vec<u8> = std::iter::once(SESSION_TYPE)
-            .chain(token.as_bytes().iter().copied())
-            .collect();
-
-        db.put(key, &[]).map_err(ErrorInternalServerError)?;
-        session.insert("auth", token)?;
-        Ok(HttpResponse::Ok().body("OK"))
-    } else {
-        Ok(HttpResponse::Unauthorized().body("Invalid password"))
+    // not using the forwarded address here, it's trivial to spoof
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(retry_after) =
+        login_attempts::begin_attempt(&db, ip, &form.username, &config.auth.login_backoff)
+    {
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((
+                header::RETRY_AFTER,
+                retry_after.num_seconds().max(1).to_string(),
+            ))
+            .body("Too many failed login attempts"));
+    }
+
+    let credentials = users::fetch_credentials(
+        &mut *metadata.acquire().await.map_err(ErrorInternalServerError)?,
+        &form.username,
+    )
+    .await
+    .map_err(ErrorInternalServerError)?;
+    let password = form.password.clone();
+    // hashing takes a while, keep it off the async workers
+    let user = web::block(move || match credentials {
+        Some((user, hash)) => {
+            (users::verify_password(&hash, &password) && !user.disabled).then_some(user)
+        }
+        None => {
+            users::verify_dummy_password(&password);
+            None
+        }
+    })
+    .await?;
+    match user {
+        Some(user) => {
+            login_attempts::record_success(&db, ip, &form.username);
+            start_session(&session, &db, user.id, &req)?;
+            Ok(HttpResponse::Ok().body("OK"))
+        }
+        None => {
+            log::warn!(
+                target: "audit",
+                "Failed login as `{}` from {}",
+                form.username,
+                ip.map_or_else(|| "unknown address".to_owned(), |ip| ip.to_string())
+            );
+            Ok(HttpResponse::Unauthorized().body("Invalid username or password"))
+        }
     }
 }
 
@@ -41,29 +203,251 @@ pub async fn logout(
     db: web::Data<Arc<FileDatabase>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     if let Some(token) = session.get::<Uuid>("auth").ok().flatten() {
-        let key: Vec<u8> = std::iter::once(SESSION_TYPE)
-            .chain(token.as_bytes().iter().copied())
-            .collect();
-        db.delete(key).ok();
+        db.delete(session_key(&token)).ok();
         session.purge();
     }
     Ok(HttpResponse::Ok().body("OK"))
 }
 
-pub fn verify(session: &Session, db: &FileDatabase) -> Result<(), HttpResponse> {
-    let verified = if let Some(token) = session.get::<Uuid>("auth").ok().flatten() {
-        let key: Vec<u8> = std::iter::once(SESSION_TYPE)
-            .chain(token.as_bytes().iter().copied())
-            .collect();
-        db.key_may_exist(&key) && db.get(key).ok().flatten().is_some()
+/// Identifies the session or API token a request was authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    Session(Uuid),
+    ApiToken(Uuid),
+}
+
+impl Credential {
+    pub fn id(self) -> Uuid {
+        match self {
+            Credential::Session(id) | Credential::ApiToken(id) => id,
+        }
+    }
+}
+
+/// Rejects GraphQL resolvers for requests authenticated with an API token, so that a token can't
+/// be used to mint further tokens, change passwords or end sessions.
+pub struct SessionGuard;
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for SessionGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        match ctx.data_opt::<Credential>() {
+            Some(Credential::Session(_)) => Ok(()),
+            _ => Err("Not allowed with an API token".into()),
+        }
+    }
+}
+
+/// Checks the `Authorization: Bearer` API token or else the session cookie, and returns the
+/// authenticated user.
+pub async fn verify(
+    req: &HttpRequest,
+    session: &Session,
+    db: &FileDatabase,
+    metadata: &MetadataDatabase,
+    config: &Config,
+) -> Result<User, AuthError> {
+    authenticate(req, session, db, metadata, config)
+        .await
+        .map(|(user, _)| user)
+}
+
+/// Like `verify`, but also returns the credential that was used.
+pub async fn authenticate(
+    req: &HttpRequest,
+    session: &Session,
+    db: &FileDatabase,
+    metadata: &MetadataDatabase,
+    config: &Config,
+) -> Result<(User, Credential), AuthError> {
+    if let Some(authorization) = req.headers().get(header::AUTHORIZATION) {
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| api_tokens::authenticate(db, token.trim()))
+            .ok_or(AuthError::Unauthorized)?;
+        let mut user = active_user(metadata, token.user)
+            .await?
+            .ok_or(AuthError::Unauthorized)?;
+        if token.read_only {
+            user.role = user.role.min(Role::Viewer);
+        }
+        return Ok((user, Credential::ApiToken(token.id)));
+    }
+
+    let token = session
+        .get::<Uuid>("auth")
+        .ok()
+        .flatten()
+        .ok_or(AuthError::Unauthorized)?;
+    let key = session_key(&token);
+    let value = db.get(&key).ok().flatten().ok_or(AuthError::Unauthorized)?;
+    let data = serde_json::from_slice::<SessionData>(&value).ok();
+    let now = Utc::now();
+    // sessions from before their metadata was stored count as expired
+    let Some(mut data) = data.filter(|data| !data.is_expired(&config.auth, now)) else {
+        db.delete(key).ok();
+        session.purge();
+        return Err(AuthError::Unauthorized);
+    };
+    if let Some(user) = active_user(metadata, data.user).await? {
+        // avoid writing to the database on every single request
+        if now - data.last_seen > Duration::minutes(1) {
+            data.last_seen = now;
+            if let Ok(value) = serde_json::to_vec(&data) {
+                db.put(key, value).ok();
+            }
+        }
+        Ok((user, Credential::Session(data.id)))
     } else {
-        false
+        db.delete(key).ok();
+        Err(AuthError::Unauthorized)
+    }
+}
+
+/// Fetches a user, unless it doesn't exist (anymore) or is disabled.
+async fn active_user(metadata: &MetadataDatabase, id: Uuid) -> Result<Option<User>, AuthError> {
+    let mut db = metadata.acquire().await.map_err(|err| {
+        log::error!("Failed connecting to the metadata database: {}", err);
+        AuthError::Unauthorized
+    })?;
+    let user = users::fetch_user(&mut db, id).await.map_err(|err| {
+        log::error!("Failed fetching user: {}", err);
+        AuthError::Unauthorized
+    })?;
+    Ok(user.filter(|user| !user.disabled))
+}
+
+/// Whether a long-lived connection may go on: its session hasn't expired or been revoked, its
+/// API token hasn't been revoked, and the user is still active.
+async fn still_valid(
+    db: &FileDatabase,
+    metadata: &MetadataDatabase,
+    config: &Config,
+    user: Uuid,
+    credential: Credential,
+) -> bool {
+    let valid = match credential {
+        Credential::Session(id) => {
+            let now = Utc::now();
+            all_sessions(db).any(|(_, data)| data.id == id && !data.is_expired(&config.auth, now))
+        }
+        Credential::ApiToken(id) => api_tokens::exists(db, id),
     };
-    if verified {
+    valid && matches!(active_user(metadata, user).await, Ok(Some(_)))
+}
+
+/// Resolves once the credential a long-lived connection was opened with is no longer valid,
+/// checked every minute.
+pub async fn revoked(
+    db: Arc<FileDatabase>,
+    metadata: MetadataDatabase,
+    config: Arc<Config>,
+    user: Uuid,
+    credential: Credential,
+) {
+    let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if !still_valid(&db, &metadata, &config, user, credential).await {
+            return;
+        }
+    }
+}
+
+/// Fails with `Forbidden` if the user doesn't have at least the given role.
+pub fn require_role(user: &User, role: Role) -> Result<(), AuthError> {
+    if user.role >= role {
         Ok(())
     } else {
-        Err(HttpResponse::Unauthorized()
-            .content_type("text/html")
-            .body(include_str!("../login.html")))
+        Err(AuthError::Forbidden)
+    }
+}
+
+/// Iterates over all stored sessions together with their database key, including ones from
+/// before their metadata was stored, which come without it.
+fn stored_sessions(
+    db: &FileDatabase,
+) -> impl Iterator<Item = (Box<[u8]>, Option<SessionData>)> + '_ {
+    db.prefix_iterator([SESSION_TYPE])
+        .take_while(|(key, _)| key.first() == Some(&SESSION_TYPE))
+        .map(|(key, value)| (key, serde_json::from_slice::<SessionData>(&value).ok()))
+}
+
+/// Iterates over all sessions with metadata together with their database key.
+fn all_sessions(db: &FileDatabase) -> impl Iterator<Item = (Box<[u8]>, SessionData)> + '_ {
+    stored_sessions(db).filter_map(|(key, data)| Some((key, data?)))
+}
+
+pub fn user_sessions(db: &FileDatabase, user: Uuid) -> Vec<SessionData> {
+    all_sessions(db)
+        .filter(|(_, data)| data.user == user)
+        .map(|(_, data)| data)
+        .collect()
+}
+
+/// Ends a session by id. Only sessions of `user` are considered, unless it's `None`.
+pub fn revoke_session(db: &FileDatabase, id: Uuid, user: Option<Uuid>) -> bool {
+    if let Some((key, _)) = all_sessions(db)
+        .find(|(_, data)| data.id == id && user.is_none_or(|user| data.user == user))
+    {
+        db.delete(key).is_ok()
+    } else {
+        false
+    }
+}
+
+/// Removes all sessions belonging to the given user.
+pub fn revoke_user_sessions(db: &FileDatabase, user: Uuid) {
+    for (key, _) in all_sessions(db).filter(|(_, data)| data.user == user) {
+        db.delete(key).ok();
+    }
+}
+
+/// Periodically deletes sessions that ran into the idle or absolute timeout, ones without
+/// metadata, and stale failed login counters.
+pub async fn sweep_sessions(db: Arc<FileDatabase>, config: Arc<Config>) {
+    let mut interval = actix_web::rt::time::interval(StdDuration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let mut count = 0;
+        let expired = stored_sessions(&db).filter(|(_, data)| {
+            data.as_ref()
+                .is_none_or(|data| data.is_expired(&config.auth, now))
+        });
+        for (key, _) in expired {
+            if db.delete(key).is_ok() {
+                count += 1;
+            }
+        }
+        if count > 0 {
+            log::info!("Removed {} expired sessions.", count);
+        }
+        let count = login_attempts::sweep(&db, &config.auth.login_backoff);
+        if count > 0 {
+            log::info!("Removed {} stale failed login counters.", count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn require_role_allows_the_role_and_above() {
+        assert!(require_role(&users::test_user(Role::Viewer), Role::Viewer).is_ok());
+        assert!(require_role(&users::test_user(Role::Editor), Role::Viewer).is_ok());
+        assert!(require_role(&users::test_user(Role::Admin), Role::Editor).is_ok());
+        assert!(matches!(
+            require_role(&users::test_user(Role::Viewer), Role::Editor),
+            Err(AuthError::Forbidden)
+        ));
+        assert!(matches!(
+            require_role(&users::test_user(Role::Editor), Role::Admin),
+            Err(AuthError::Forbidden)
+        ));
     }
 }
@@ -0,0 +1,352 @@
+use std::sync::OnceLock;
+
+use anyhow::Error;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_graphql::{Context, Enum, Guard, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+use crate::config::{self, Config};
+
+/// Access level of a user, ordered from least to most privileged.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Enum, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    /// Can look things up
+    Viewer,
+    /// Can also add, change and delete inventory
+    Editor,
+    /// Can also manage users
+    Admin,
+}
+
+/// Rejects GraphQL resolvers for users below the given role.
+pub struct RoleGuard {
+    role: Role,
+}
+
+impl RoleGuard {
+    pub fn new(role: Role) -> Self {
+        Self { role }
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        match ctx.data_opt::<User>() {
+            Some(user) if user.role >= self.role => Ok(()),
+            _ => Err("Forbidden".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct User {
+    pub id: Uuid,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub username: String,
+    pub disabled: bool,
+    pub role: Role,
+}
+
+/// An enabled user with the given role, only in memory
+#[cfg(test)]
+pub fn test_user(role: Role) -> User {
+    User {
+        id: Uuid::new_v4(),
+        created: Utc::now(),
+        updated: Utc::now(),
+        username: "someone".to_owned(),
+        disabled: false,
+        role,
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    anyhow::ensure!(!password.is_empty(), "Password must not be empty");
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// `hash_password` on a blocking thread, hashing takes too long for the async workers.
+pub async fn hash_password_async(password: String) -> Result<String, Error> {
+    actix_web::web::block(move || hash_password(&password)).await?
+}
+
+/// `verify_password` on a blocking thread, like `hash_password_async`.
+pub async fn verify_password_async(hash: String, password: String) -> Result<bool, Error> {
+    Ok(actix_web::web::block(move || verify_password(&hash, &password)).await?)
+}
+
+/// Verifies against a throwaway hash, so that unknown user names take as long as wrong passwords.
+pub fn verify_dummy_password(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH.get_or_init(|| hash_password("dummy").unwrap_or_default());
+    verify_password(hash, password);
+}
+
+pub async fn fetch_user(db: &mut SqliteConnection, id: Uuid) -> Result<Option<User>, Error> {
+    match sqlx::query!(
+        r#"SELECT uuid, created, updated, username, disabled, role as "role: Role" FROM users WHERE uuid = ?"#,
+        id
+    )
+    .fetch_one(db)
+    .await
+    {
+        Ok(row) => Ok(Some(User {
+            id: Uuid::from_slice(&row.uuid).unwrap(),
+            created: DateTime::from_utc(row.created, Utc),
+            updated: DateTime::from_utc(row.updated, Utc),
+            username: row.username,
+            disabled: row.disabled,
+            role: row.role,
+        })),
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Looks up a user by name, returning it together with its password hash.
+pub async fn fetch_credentials(
+    db: &mut SqliteConnection,
+    username: &str,
+) -> Result<Option<(User, String)>, Error> {
+    match sqlx::query!(
+        r#"SELECT uuid, created, updated, username, password_hash, disabled, role as "role: Role" FROM users WHERE username = ?"#,
+        username
+    )
+        .fetch_one(db)
+        .await
+    {
+        Ok(row) => Ok(Some((
+            User {
+                id: Uuid::from_slice(&row.uuid).unwrap(),
+                created: DateTime::from_utc(row.created, Utc),
+                updated: DateTime::from_utc(row.updated, Utc),
+                username: row.username,
+                disabled: row.disabled,
+                role: row.role,
+            },
+            row.password_hash,
+        ))),
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn create_user(
+    db: &mut SqliteConnection,
+    username: &str,
+    password: &str,
+    role: Role,
+) -> Result<Uuid, Error> {
+    let hash = hash_password_async(password.to_owned()).await?;
+    insert_user(db, username, &hash, role).await
+}
+
+async fn insert_user(
+    db: &mut SqliteConnection,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> Result<Uuid, Error> {
+    anyhow::ensure!(!username.is_empty(), "Username must not be empty");
+    let uuid = Uuid::new_v4();
+    let now = Utc::now();
+    sqlx::query!(
+        "INSERT INTO users (uuid, created, updated, username, password_hash, role) VALUES (?, ?, ?, ?, ?, ?)",
+        uuid,
+        now,
+        now,
+        username,
+        password_hash,
+        role
+    )
+    .execute(db)
+    .await?;
+    Ok(uuid)
+}
+
+/// Finds the local user linked to an account at an identity provider, linking or creating one
+/// on first login. Existing users are only linked through a verified email address matching their
+/// user name, and keep their role unless `update_roles` is set.
+pub async fn link_external_user(
+    db: &mut SqliteConnection,
+    config: &config::Oidc,
+    subject: &str,
+    username: &str,
+    verified_email: Option<&str>,
+    role: Role,
+) -> Result<User, Error> {
+    let linked = sqlx::query_scalar!(
+        "SELECT user FROM user_identities WHERE issuer = ? AND subject = ?",
+        config.issuer,
+        subject
+    )
+    .fetch_optional(&mut *db)
+    .await?;
+    let (id, created) = if let Some(id) = linked {
+        (Uuid::from_slice(&id)?, false)
+    } else {
+        let existing = match verified_email {
+            Some(email) if config.link_existing_users => fetch_credentials(&mut *db, email)
+                .await?
+                .map(|(user, _)| user.id),
+            _ => None,
+        };
+        let (id, created) = match existing {
+            Some(id) => (id, false),
+            None => {
+                if fetch_credentials(&mut *db, username).await?.is_some() {
+                    anyhow::bail!("User name `{}` is already taken", username);
+                }
+                // users without a password hash can't log in locally
+                (insert_user(&mut *db, username, "", role).await?, true)
+            }
+        };
+        let now = Utc::now();
+        sqlx::query!(
+            "INSERT INTO user_identities (issuer, subject, user, created) VALUES (?, ?, ?, ?)",
+            config.issuer,
+            subject,
+            id,
+            now
+        )
+        .execute(&mut *db)
+        .await?;
+        (id, created)
+    };
+    if config.update_roles && !created {
+        let now = Utc::now();
+        sqlx::query!(
+            "UPDATE users SET updated = ?, role = ? WHERE uuid = ? AND role != ?",
+            now,
+            role,
+            id,
+            role
+        )
+        .execute(&mut *db)
+        .await?;
+    }
+    fetch_user(db, id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Linked user doesn't exist"))
+}
+
+/// Creates the initial admin account from `auth.password` if there are no users yet.
+pub async fn bootstrap_admin(db: &mut SqliteConnection, config: &Config) -> Result<(), Error> {
+    let count = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&mut *db)
+        .await?;
+    if count > 0 {
+        return Ok(());
+    }
+    if let Some(password) = &config.auth.password {
+        create_user(db, &config.auth.admin_username, password, Role::Admin).await?;
+        log::info!(
+            "Created initial user `{}` from the configured password.",
+            config.auth.admin_username
+        );
+    } else {
+        log::warn!("There are no users and no `auth.password` is configured, nobody can log in.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+
+    use super::*;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        #[graphql(guard = "RoleGuard::new(Role::Editor)")]
+        async fn edit(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(Role::Viewer < Role::Editor);
+        assert!(Role::Editor < Role::Admin);
+        assert_eq!(Role::Admin.min(Role::Viewer), Role::Viewer);
+        assert_eq!(serde_json::to_string(&Role::Editor).unwrap(), "\"editor\"");
+        assert_eq!(
+            serde_json::from_str::<Role>("\"admin\"").unwrap(),
+            Role::Admin
+        );
+    }
+
+    #[actix_web::test]
+    async fn role_guard_needs_at_least_the_role() {
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        for (role, allowed) in [
+            (Role::Viewer, false),
+            (Role::Editor, true),
+            (Role::Admin, true),
+        ] {
+            let response = schema
+                .execute(Request::new("{ edit }").data(test_user(role)))
+                .await;
+            assert_eq!(response.errors.is_empty(), allowed, "{:?}", role);
+        }
+        let response = schema.execute("{ edit }").await;
+        assert_eq!(response.errors[0].message, "Forbidden");
+    }
+
+    #[test]
+    fn verifies_only_the_hashed_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "correct horse "));
+        assert!(!verify_password(&hash, ""));
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        let first = hash_password("secret").unwrap();
+        let second = hash_password("secret").unwrap();
+        assert_ne!(first, second);
+        assert!(verify_password(&first, "secret"));
+        assert!(verify_password(&second, "secret"));
+    }
+
+    #[test]
+    fn refuses_empty_passwords() {
+        assert!(hash_password("").is_err());
+    }
+
+    #[test]
+    fn invalid_hashes_match_nothing() {
+        assert!(!verify_password("", ""));
+        assert!(!verify_password("secret", "secret"));
+        assert!(!verify_password("$argon2id$v=19$garbage", "secret"));
+    }
+}